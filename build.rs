@@ -0,0 +1,4 @@
+fn main() {
+    // Migrations are embedded into the binary, so it needs rebuilding when they change
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -1 +1,11 @@
-ALTER TABLE `points` DROP COLUMN `dir`;
\ No newline at end of file
+-- Rebuild the table rather than using DROP COLUMN, which older SQLite versions lack
+CREATE TABLE points_old (
+  "id" INTEGER PRIMARY KEY NOT NULL,
+  "name" VARCHAR NOT NULL,
+  "path" VARCHAR,
+  "hash" VARCHAR UNIQUE NOT NULL
+);
+INSERT INTO points_old ("id", "name", "path", "hash")
+  SELECT "id", "name", "path", "hash" FROM points;
+DROP TABLE points;
+ALTER TABLE points_old RENAME TO points;
@@ -0,0 +1,32 @@
+CREATE TABLE points_old (
+  "id" INTEGER PRIMARY KEY NOT NULL,
+  "name" VARCHAR NOT NULL,
+  "path" VARCHAR,
+  "hash" VARCHAR UNIQUE NOT NULL,
+  "dir" BOOLEAN NOT NULL DEFAULT false
+);
+INSERT INTO points_old ("id", "name", "path", "hash", "dir")
+  SELECT "id", "name", "path", "hash", "dir" FROM points;
+DROP TABLE points;
+ALTER TABLE points_old RENAME TO points;
+
+CREATE TABLE tags_old (
+  "id" INTEGER PRIMARY KEY NOT NULL,
+  "name" VARCHAR NOT NULL,
+  "value" VARCHAR,
+  "sort_value" BIGINT
+);
+INSERT INTO tags_old ("id", "name", "value", "sort_value")
+  SELECT "id", "name", "value", "sort_value" FROM tags;
+DROP TABLE tags;
+ALTER TABLE tags_old RENAME TO tags;
+
+CREATE TABLE joins_old (
+  "id" INTEGER PRIMARY KEY NOT NULL,
+  "tag_id" INTEGER NOT NULL,
+  "point_id" INTEGER NOT NULL
+);
+INSERT INTO joins_old ("id", "tag_id", "point_id")
+  SELECT "id", "tag_id", "point_id" FROM joins;
+DROP TABLE joins;
+ALTER TABLE joins_old RENAME TO joins;
@@ -0,0 +1,35 @@
+-- Recreate the tables with AUTOINCREMENT so ids are assigned by SQLite and
+-- never reused after a delete, keeping every existing id as-is.
+
+CREATE TABLE points_new (
+  "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  "name" VARCHAR NOT NULL,
+  "path" VARCHAR,
+  "hash" VARCHAR UNIQUE NOT NULL,
+  "dir" BOOLEAN NOT NULL DEFAULT false
+);
+INSERT INTO points_new ("id", "name", "path", "hash", "dir")
+  SELECT "id", "name", "path", "hash", "dir" FROM points;
+DROP TABLE points;
+ALTER TABLE points_new RENAME TO points;
+
+CREATE TABLE tags_new (
+  "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  "name" VARCHAR NOT NULL,
+  "value" VARCHAR,
+  "sort_value" BIGINT
+);
+INSERT INTO tags_new ("id", "name", "value", "sort_value")
+  SELECT "id", "name", "value", "sort_value" FROM tags;
+DROP TABLE tags;
+ALTER TABLE tags_new RENAME TO tags;
+
+CREATE TABLE joins_new (
+  "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  "tag_id" INTEGER NOT NULL,
+  "point_id" INTEGER NOT NULL
+);
+INSERT INTO joins_new ("id", "tag_id", "point_id")
+  SELECT "id", "tag_id", "point_id" FROM joins;
+DROP TABLE joins;
+ALTER TABLE joins_new RENAME TO joins;
@@ -0,0 +1,14 @@
+DROP INDEX points_path;
+
+DROP INDEX tags_name_without_value;
+DROP INDEX tags_name_value;
+
+CREATE TABLE joins_old (
+  "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  "tag_id" INTEGER NOT NULL,
+  "point_id" INTEGER NOT NULL
+);
+INSERT INTO joins_old ("id", "tag_id", "point_id")
+  SELECT "id", "tag_id", "point_id" FROM joins;
+DROP TABLE joins;
+ALTER TABLE joins_old RENAME TO joins;
@@ -0,0 +1,39 @@
+-- Drop joins left behind by removed points or tags first, merging would set their tag to NULL
+DELETE FROM joins WHERE "point_id" NOT IN (SELECT "id" FROM points);
+DELETE FROM joins WHERE "tag_id" NOT IN (SELECT "id" FROM tags);
+
+-- Merge duplicate tags into the oldest one with the same name and value
+UPDATE joins SET "tag_id" = (
+  SELECT MIN(t2."id") FROM tags t1, tags t2
+  WHERE t1."id" = joins."tag_id"
+    AND t2."name" = t1."name"
+    AND t2."value" IS t1."value"
+);
+DELETE FROM tags WHERE "id" NOT IN (
+  SELECT MIN("id") FROM tags GROUP BY "name", "value"
+);
+
+-- Drop the duplicate joins merging left
+DELETE FROM joins WHERE "id" NOT IN (
+  SELECT MIN("id") FROM joins GROUP BY "tag_id", "point_id"
+);
+
+CREATE TABLE joins_new (
+  "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  "tag_id" INTEGER NOT NULL REFERENCES tags ("id") ON DELETE CASCADE,
+  "point_id" INTEGER NOT NULL REFERENCES points ("id") ON DELETE CASCADE
+);
+INSERT INTO joins_new ("id", "tag_id", "point_id")
+  SELECT "id", "tag_id", "point_id" FROM joins;
+DROP TABLE joins;
+ALTER TABLE joins_new RENAME TO joins;
+
+-- Also serves as the index for looking up joins by tag
+CREATE UNIQUE INDEX joins_tag_id_point_id ON joins ("tag_id", "point_id");
+CREATE INDEX joins_point_id ON joins ("point_id");
+
+-- NULL values never conflict in a unique index, so valueless tags need their own
+CREATE UNIQUE INDEX tags_name_value ON tags ("name", "value");
+CREATE UNIQUE INDEX tags_name_without_value ON tags ("name") WHERE "value" IS NULL;
+
+CREATE INDEX points_path ON points ("path");
@@ -0,0 +1,4 @@
+CREATE TABLE scan_progress (
+  "store_dir" VARCHAR PRIMARY KEY NOT NULL,
+  "last_path" VARCHAR NOT NULL
+);
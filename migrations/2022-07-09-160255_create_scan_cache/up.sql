@@ -0,0 +1,9 @@
+CREATE TABLE scan_cache (
+  "path" VARCHAR PRIMARY KEY NOT NULL,
+  "store_dir" VARCHAR NOT NULL,
+  "size" BIGINT NOT NULL,
+  "mtime" BIGINT NOT NULL,
+  "inode" BIGINT NOT NULL
+);
+
+CREATE INDEX scan_cache_store_dir ON scan_cache ("store_dir");
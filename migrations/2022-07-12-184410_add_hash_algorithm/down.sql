@@ -0,0 +1,16 @@
+DROP TABLE settings;
+
+-- Rebuild the table rather than using DROP COLUMN, which older SQLite versions lack
+CREATE TABLE points_old (
+  "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  "name" VARCHAR NOT NULL,
+  "path" VARCHAR,
+  "hash" VARCHAR UNIQUE NOT NULL,
+  "dir" BOOLEAN NOT NULL DEFAULT false
+);
+INSERT INTO points_old ("id", "name", "path", "hash", "dir")
+  SELECT "id", "name", "path", "hash", "dir" FROM points;
+DROP TABLE points;
+ALTER TABLE points_old RENAME TO points;
+
+CREATE INDEX points_path ON points ("path");
@@ -0,0 +1,7 @@
+-- Everything hashed so far was hashed with BLAKE2b
+ALTER TABLE points ADD COLUMN "hash_algorithm" VARCHAR NOT NULL DEFAULT 'blake2b';
+
+CREATE TABLE settings (
+  "name" VARCHAR PRIMARY KEY NOT NULL,
+  "value" VARCHAR NOT NULL
+);
@@ -0,0 +1 @@
+UPDATE points SET hash_algorithm = replace(hash_algorithm, '-flat', '') WHERE dir;
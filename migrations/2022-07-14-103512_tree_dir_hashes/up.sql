@@ -0,0 +1,2 @@
+-- Dir hashes used to be made differently, marking them lets `ffs rehash` find them
+UPDATE points SET hash_algorithm = hash_algorithm || '-flat' WHERE dir;
@@ -0,0 +1,13 @@
+-- Rebuild the table rather than using DROP COLUMN, which older SQLite versions lack
+CREATE TABLE joins_old (
+  "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  "tag_id" INTEGER NOT NULL REFERENCES tags ("id") ON DELETE CASCADE,
+  "point_id" INTEGER NOT NULL REFERENCES points ("id") ON DELETE CASCADE
+);
+INSERT INTO joins_old ("id", "tag_id", "point_id")
+  SELECT "id", "tag_id", "point_id" FROM joins;
+DROP TABLE joins;
+ALTER TABLE joins_old RENAME TO joins;
+
+CREATE UNIQUE INDEX joins_tag_id_point_id ON joins ("tag_id", "point_id");
+CREATE INDEX joins_point_id ON joins ("point_id");
@@ -0,0 +1,3 @@
+-- Where each join came from: 'manual', 'store' or 'auto:' and the autotagger's name
+-- Joins from before this was kept can't be told apart, and get claimed by the next thing that adds them
+ALTER TABLE joins ADD COLUMN "source" VARCHAR NOT NULL DEFAULT 'unknown';
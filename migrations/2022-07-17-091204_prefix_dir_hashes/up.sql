@@ -0,0 +1,2 @@
+-- Dir hashes are now set apart from file hashes, marking them gets them rehashed right after migrating
+UPDATE points SET hash_algorithm = hash_algorithm || '-unprefixed' WHERE dir;
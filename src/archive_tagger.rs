@@ -0,0 +1,385 @@
+use crate::autotagger::{flag, number, value, Autotagger, TagSource};
+use crate::utils::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// How many of the most common file types in an archive get a `contains` tag, source tarballs would have dozens otherwise
+const CONTAINS_LIMIT: usize = 16;
+
+/// Package metadata in wheels is a few KB at most
+const METADATA_LIMIT: u64 = 1024 * 1024;
+
+/// Tars have to be read all the way through to list them, past this much decompressed data the listing is left partial
+const TAR_LIMIT: u64 = 256 * 1024 * 1024;
+
+struct ArchiveEntry {
+    name: String,
+    size: u64,
+    is_dir: bool,
+}
+
+/// What's in an archive, and what kind of archive it turned out to be
+struct ArchiveListing {
+    kind: &'static str,
+    compression: Option<&'static str>,
+    entries: Vec<ArchiveEntry>,
+    /// False when `TAR_LIMIT` cut the listing short, so there are more entries than it has
+    complete: bool,
+    /// Tags for special archives, like the name and version of a package
+    extra: TagEntries,
+}
+
+impl ArchiveListing {
+    fn new(kind: &'static str, entries: Vec<ArchiveEntry>) -> ArchiveListing {
+        ArchiveListing {
+            kind,
+            compression: None,
+            entries,
+            complete: true,
+            extra: Vec::new(),
+        }
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.entries.iter().any(|x| x.name == name)
+    }
+}
+
+/// A Python package's `Name` and `Version` from its `METADATA`, which is written like email headers
+fn wheel_package(zip: &mut zip::ZipArchive<fs::File>, metadata_path: &str) -> TagEntries {
+    let mut metadata = String::new();
+
+    let read = zip
+        .by_name(metadata_path)
+        .map_err(|err| err.to_string())
+        .and_then(|x| {
+            x.take(METADATA_LIMIT)
+                .read_to_string(&mut metadata)
+                .map_err(|err| err.to_string())
+        });
+
+    if let Err(err) = read {
+        warn!("Error reading {}: {}", metadata_path, err);
+        return vec![];
+    }
+
+    let mut tags = Vec::new();
+
+    // The headers end at the first blank line, the description comes after
+    for line in metadata.lines().take_while(|x| !x.is_empty()) {
+        if let Some(name) = line.strip_prefix("Name:") {
+            tags.push(value("package", name.trim()));
+        }
+
+        if let Some(version) = line.strip_prefix("Version:") {
+            tags.push(value("version", version.trim()));
+        }
+    }
+
+    tags
+}
+
+fn zip_listing(path: &Path) -> Result<ArchiveListing, String> {
+    let file = fs::File::open(path).map_err(|err| err.to_string())?;
+    let mut zip = zip::ZipArchive::new(file).map_err(|err| err.to_string())?;
+
+    let mut entries = Vec::new();
+
+    // Raw so encrypted entries can still be listed
+    for i in 0..zip.len() {
+        let entry = zip.by_index_raw(i).map_err(|err| err.to_string())?;
+
+        entries.push(ArchiveEntry {
+            name: entry.name().to_string(),
+            size: entry.size(),
+            is_dir: entry.is_dir(),
+        });
+    }
+
+    let mut listing = ArchiveListing::new("zip", entries);
+
+    let dist_info = listing
+        .entries
+        .iter()
+        .filter_map(|x| x.name.strip_suffix(".dist-info/WHEEL"))
+        .find(|x| !x.contains('/'))
+        .map(|x| x.to_string());
+
+    // APKs are signed like JARs, so they have a manifest too
+    if listing.has("AndroidManifest.xml") && listing.has("classes.dex") {
+        listing.kind = "apk";
+        listing.extra = vec![flag("android"), value("language", "java")];
+    } else if let Some(dist_info) = dist_info {
+        listing.kind = "wheel";
+        listing.extra = vec![value("language", "python")];
+        listing.extra.append(&mut wheel_package(
+            &mut zip,
+            &format!("{}.dist-info/METADATA", dist_info),
+        ));
+    } else if listing.has("META-INF/MANIFEST.MF") {
+        listing.kind = "jar";
+        listing.extra = vec![value("language", "java")];
+    }
+
+    Ok(listing)
+}
+
+/// Whether a block is a tar header, going by its checksum since old tars don't have the `ustar` magic
+fn is_tar_header(block: &[u8]) -> bool {
+    if block.len() < 512 {
+        return false;
+    }
+
+    let Some(checksum) = std::str::from_utf8(&block[148..156])
+        .ok()
+        .and_then(|x| u64::from_str_radix(x.trim_matches(|c| c == ' ' || c == '\0'), 8).ok())
+    else {
+        return false;
+    };
+
+    // The checksum is counted with its own field as spaces
+    let sum = block[..512]
+        .iter()
+        .enumerate()
+        .map(|(i, x)| if (148..156).contains(&i) { b' ' } else { *x } as u64)
+        .sum::<u64>();
+
+    sum == checksum
+}
+
+/// The entries of a tar and whether that's all of them, or None if it isn't one, like a gzipped file that's just a file
+fn tar_entries(reader: impl Read) -> Result<Option<(Vec<ArchiveEntry>, bool)>, String> {
+    let mut reader = reader.take(TAR_LIMIT);
+
+    let mut header = Vec::new();
+    (&mut reader)
+        .take(512)
+        .read_to_end(&mut header)
+        .map_err(|err| err.to_string())?;
+
+    if !is_tar_header(&header) {
+        return Ok(None);
+    }
+
+    let mut entries = Vec::new();
+
+    let read = (|| -> io::Result<()> {
+        let mut archive = tar::Archive::new(io::Cursor::new(header).chain(&mut reader));
+
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let header = entry.header();
+
+            entries.push(ArchiveEntry {
+                name: entry.path()?.to_string_lossy().to_string(),
+                size: header.size().unwrap_or(0),
+                is_dir: header.entry_type().is_dir(),
+            });
+        }
+
+        Ok(())
+    })();
+
+    // Hitting the limit cuts the tar off partway, which it sees as being broken
+    let complete = reader.limit() > 0;
+
+    match read {
+        Err(err) if complete => Err(err.to_string()),
+        _ => Ok(Some((entries, complete))),
+    }
+}
+
+/// Whether a crate's version is all that's left of `name-version`, which has to be semver
+fn is_crate_version(text: &str) -> bool {
+    let core = text.split(['-', '+']).next().unwrap_or("");
+    let parts = core.split('.').collect::<Vec<&str>>();
+
+    parts.len() == 3
+        && parts
+            .iter()
+            .all(|x| !x.is_empty() && x.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// A tar, compressed or not, or a file that's only compressed, which isn't an archive at all
+fn tar_listing(
+    path: &Path,
+    compression: Option<&'static str>,
+) -> Result<Option<ArchiveListing>, String> {
+    let file = BufReader::new(fs::File::open(path).map_err(|err| err.to_string())?);
+
+    let entries = match compression {
+        Some("gzip") => tar_entries(flate2::read::MultiGzDecoder::new(file)),
+        Some("xz") => tar_entries(xz2::read::XzDecoder::new_multi_decoder(file)),
+        Some("zstd") => {
+            tar_entries(zstd::Decoder::with_buffer(file).map_err(|err| err.to_string())?)
+        }
+        _ => tar_entries(file),
+    }?;
+
+    let Some((entries, complete)) = entries else {
+        return Ok(None);
+    };
+
+    let mut listing = ArchiveListing::new("tar", entries);
+    listing.compression = compression;
+    listing.complete = complete;
+
+    // Crates are a gzipped tar of a single `name-version` dir with the package in it
+    let is_crate = path.extension().map(|x| x == "crate").unwrap_or(false);
+
+    let crate_dir = listing
+        .entries
+        .iter()
+        .filter_map(|x| x.name.strip_suffix("/Cargo.toml"))
+        .find(|x| !x.contains('/'))
+        .map(|x| x.to_string());
+
+    if let (true, Some(crate_dir)) = (is_crate, crate_dir) {
+        listing.kind = "crate";
+        listing.extra = vec![value("language", "rust"), flag("cargo")];
+
+        // Names can have dashes and digits, like `sha-1`, and versions can have dashes too, but names can't have dots
+        let split = crate_dir
+            .rmatch_indices('-')
+            .map(|(i, _)| i)
+            .find(|i| !crate_dir[..*i].contains('.') && is_crate_version(&crate_dir[i + 1..]));
+
+        if let Some(split) = split {
+            listing.extra.push(value("package", &crate_dir[..split]));
+            listing
+                .extra
+                .push(value("version", &crate_dir[split + 1..]));
+        }
+    }
+
+    Ok(Some(listing))
+}
+
+fn sevenz_listing(path: &Path) -> Result<ArchiveListing, String> {
+    let archive = sevenz_rust::Archive::open(path).map_err(|err| err.to_string())?;
+
+    let entries = archive
+        .files
+        .iter()
+        .filter(|x| !x.is_anti_item)
+        .map(|x| ArchiveEntry {
+            name: x.name.clone(),
+            size: x.size,
+            is_dir: x.is_directory,
+        })
+        .collect();
+
+    Ok(ArchiveListing::new("7z", entries))
+}
+
+/// Picks the reader for a file from its first bytes, since compressed tars only have their extension to go by otherwise
+fn archive_listing(path: &Path) -> Result<Option<ArchiveListing>, String> {
+    let mut start = [0; 8];
+    let read = fs::File::open(path)
+        .and_then(|mut x| x.read(&mut start))
+        .map_err(|err| err.to_string())?;
+
+    match &start[..read] {
+        [b'P', b'K', 3, 4, ..] | [b'P', b'K', 5, 6, ..] => zip_listing(path).map(Some),
+        [0x37, 0x7A, 0xBC, 0xAF, 0x27, 0x1C, ..] => sevenz_listing(path).map(Some),
+        [0x1F, 0x8B, ..] => tar_listing(path, Some("gzip")),
+        [0xFD, b'7', b'z', b'X', b'Z', 0, ..] => tar_listing(path, Some("xz")),
+        [0x28, 0xB5, 0x2F, 0xFD, ..] => tar_listing(path, Some("zstd")),
+        _ => tar_listing(path, None),
+    }
+}
+
+/// The most common extensions of the files in an archive, as a stand in for their types
+fn contained_types(entries: &[ArchiveEntry]) -> Vec<String> {
+    let mut counts = HashMap::new();
+
+    for entry in entries.iter().filter(|x| !x.is_dir) {
+        let extension = Path::new(&entry.name)
+            .extension()
+            .and_then(|x| x.to_str())
+            .map(|x| x.to_lowercase())
+            .filter(|x| x.len() <= 8 && x.chars().all(|c| c.is_ascii_alphanumeric()));
+
+        if let Some(extension) = extension {
+            *counts.entry(extension).or_insert(0) += 1;
+        }
+    }
+
+    let mut types = counts.into_iter().collect::<Vec<(String, usize)>>();
+    types.sort_by(|(a_type, a_count), (b_type, b_count)| {
+        b_count.cmp(a_count).then(a_type.cmp(b_type))
+    });
+
+    types
+        .into_iter()
+        .take(CONTAINS_LIMIT)
+        .map(|(extension, _)| extension)
+        .collect()
+}
+
+fn listing_tags(listing: ArchiveListing) -> TagEntries {
+    let mut tags = vec![flag("archive"), value("type", listing.kind)];
+
+    if let Some(compression) = listing.compression {
+        tags.push(value("compression", compression));
+    }
+
+    // Counts from part of an archive would only be wrong
+    if listing.complete {
+        let files = listing.entries.iter().filter(|x| !x.is_dir);
+
+        tags.push(number("entries", &files.clone().count().to_string()));
+        tags.push(number(
+            "uncompressed_size",
+            &files.map(|x| x.size).sum::<u64>().to_string(),
+        ));
+    }
+
+    for contained in contained_types(&listing.entries) {
+        tags.push(value("contains", &contained));
+    }
+
+    tags.extend(listing.extra);
+
+    tags
+}
+
+/// Lists zips, tars, compressed or not, and 7z archives, recognising JARs, APKs, wheels and crates among them
+pub struct ArchiveTagger;
+
+impl Autotagger for ArchiveTagger {
+    fn name(&self) -> &str {
+        "archive"
+    }
+
+    fn applies_to(&self, source: &TagSource) -> bool {
+        source.magic_parts().any(|x| {
+            x == "Zip archive data"
+                || x.starts_with("Java archive data")
+                || x.starts_with("Android package")
+                || x.starts_with("gzip compressed data")
+                || x.starts_with("XZ compressed data")
+                || x.starts_with("Zstandard compressed data")
+                || x.starts_with("POSIX tar archive")
+                || x.starts_with("tar archive")
+                || x.starts_with("7-zip archive data")
+        }) || source
+            .extension()
+            .map(|x| {
+                [
+                    "zip", "jar", "apk", "whl", "crate", "tar", "tgz", "txz", "tzst", "7z",
+                ]
+                .contains(&x.to_lowercase().as_str())
+            })
+            .unwrap_or(false)
+    }
+
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String> {
+        let listing = archive_listing(source.path)
+            .map_err(|err| format!("error listing archive: {}", err))?;
+
+        Ok(listing.map(listing_tags).unwrap_or_default())
+    }
+}
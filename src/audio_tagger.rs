@@ -0,0 +1,451 @@
+use crate::autotagger::{self, flag, last_per_name, number, value, Autotagger, TagSource};
+use crate::utils::*;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+use symphonia::core::probe::Hint;
+
+use id3::TagLike;
+
+/// What the audio itself is like, as opposed to what it's tagged with
+#[derive(Default)]
+struct StreamInfo {
+    /// In seconds
+    duration: Option<f64>,
+    sample_rate: Option<u32>,
+    channels: Option<u32>,
+}
+
+/// The `type` for audio formats we can read more than ID3 from
+fn audio_type(source: &TagSource) -> Option<&'static str> {
+    let magic = source.magic.as_str();
+
+    if magic.starts_with("FLAC audio") || magic.starts_with("Ogg data, FLAC audio") {
+        Some("flac")
+    } else if magic.starts_with("Ogg data, Vorbis audio") {
+        Some("ogg")
+    } else if magic.starts_with("Ogg data, Opus audio") {
+        Some("opus")
+    } else if magic.contains("(.M4A) Audio") || magic.contains("(.M4B) Audio") {
+        Some("m4a")
+    } else if magic.starts_with("Monkey's Audio") {
+        Some("ape")
+    } else {
+        match source.extension().map(|x| x.to_lowercase()).as_deref() {
+            Some("flac") => Some("flac"),
+            Some("ogg" | "oga") => Some("ogg"),
+            Some("opus") => Some("opus"),
+            Some("m4a" | "m4b") => Some("m4a"),
+            Some("ape") => Some("ape"),
+            _ => None,
+        }
+    }
+}
+
+/// The leading number of things like `3`, `03` and `3/12`
+fn leading_number(text: &str) -> Option<i64> {
+    text.trim()
+        .split(|x: char| !x.is_ascii_digit())
+        .next()
+        .and_then(|x| x.parse::<i64>().ok())
+}
+
+/// A tag from the common name for a text field, which every format has its own spelling of
+fn field_tag(name: &str, text: &str) -> Option<TagEntry> {
+    let text = text.trim_matches(char::from(0)).trim();
+
+    if text.is_empty() {
+        return None;
+    }
+
+    match name {
+        "album" | "artist" | "album_artist" | "genre" | "comment" => {
+            autotagger::text_tag(name, text)
+        }
+        "year" => {
+            // Dates are often written in full, the year is all we want from them
+            let year = leading_number(text).filter(|x| (1000..10000).contains(x))?;
+            Some(number("year", &year.to_string()))
+        }
+        "track" | "disc" => leading_number(text)
+            .filter(|x| *x > 0)
+            .map(|x| number(name, &x.to_string())),
+        _ => None,
+    }
+}
+
+fn revision_tags(revision: &MetadataRevision) -> TagEntries {
+    revision
+        .tags()
+        .iter()
+        .filter_map(|tag| {
+            let name = match tag.std_key? {
+                StandardTagKey::Album => "album",
+                StandardTagKey::Artist => "artist",
+                StandardTagKey::AlbumArtist => "album_artist",
+                StandardTagKey::Genre => "genre",
+                StandardTagKey::Comment => "comment",
+                StandardTagKey::Date
+                | StandardTagKey::ReleaseDate
+                | StandardTagKey::OriginalDate => "year",
+                StandardTagKey::TrackNumber => "track",
+                StandardTagKey::DiscNumber => "disc",
+                _ => return None,
+            };
+
+            field_tag(name, &tag.value.to_string())
+        })
+        .collect()
+}
+
+/// Tags and stream info from whatever symphonia can read, Vorbis comments in FLAC and Ogg files and atoms in MP4s
+fn symphonia_tags(source: &TagSource) -> Result<(TagEntries, StreamInfo), String> {
+    let file = fs::File::open(source.path).map_err(|err| err.to_string())?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = source.extension() {
+        hint.with_extension(extension);
+    }
+
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|err| err.to_string())?;
+
+    let mut tags = Vec::new();
+
+    // Metadata from before the container, like ID3 in front of a FLAC file, then the container's own
+    if let Some(mut metadata) = probed.metadata.get() {
+        if let Some(revision) = metadata.skip_to_latest() {
+            tags.append(&mut revision_tags(revision));
+        }
+    }
+
+    if let Some(revision) = probed.format.metadata().skip_to_latest() {
+        tags.append(&mut revision_tags(revision));
+    }
+
+    let mut info = StreamInfo::default();
+
+    if let Some(track) = probed.format.default_track() {
+        let params = &track.codec_params;
+
+        info.sample_rate = params.sample_rate;
+        info.channels = params.channels.map(|x| x.count() as u32);
+
+        info.duration = match (params.n_frames, params.time_base, params.sample_rate) {
+            (Some(frames), Some(time_base), _) => {
+                let time = time_base.calc_time(frames);
+                Some(time.seconds as f64 + time.frac)
+            }
+            (Some(frames), None, Some(sample_rate)) => Some(frames as f64 / sample_rate as f64),
+            _ => None,
+        };
+    }
+
+    Ok((tags, info))
+}
+
+fn id3_tags(path: &Path) -> Result<TagEntries, String> {
+    let mut tags = Vec::new();
+
+    let id3_tag =
+        id3::Tag::read_from_path(path).map_err(|err| format!("error reading ID3 tag: {}", err))?;
+
+    if let Some(album) = id3_tag.album() {
+        tags.extend(field_tag("album", album));
+    }
+
+    if let Some(artist) = id3_tag.artist() {
+        tags.extend(field_tag("artist", artist));
+    }
+
+    if let Some(album_artist) = id3_tag.album_artist() {
+        tags.extend(field_tag("album_artist", album_artist));
+    }
+
+    // if let Some(title) = id3_tag.title() {
+    //     tags.push(value("title", title));
+    // }
+
+    if let Some(genre) = id3_tag.genre() {
+        tags.extend(field_tag("genre", genre));
+    }
+
+    if let Some(year) = id3_tag.year() {
+        tags.push((
+            "year".to_string(),
+            Some((year.to_string(), Some(year as i64))),
+        ));
+    }
+
+    if let Some(track) = id3_tag.track() {
+        tags.extend(field_tag("track", &track.to_string()));
+    }
+
+    if let Some(disc) = id3_tag.disc() {
+        tags.extend(field_tag("disc", &disc.to_string()));
+    }
+
+    let mut comments = id3_tag.comments();
+    if let Some(comment) = comments.next() {
+        tags.extend(field_tag("comment", &comment.text));
+    }
+
+    Ok(tags)
+}
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+/// APEv2 tags, which sit at the end of Monkey's Audio files and sometimes MP3s, before any ID3v1 tag
+fn ape_tags(path: &Path) -> io::Result<TagEntries> {
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let mut footer = [0; 32];
+
+    let mut footer_at = None;
+
+    for skip in [0, 128] {
+        if len < 32 + skip {
+            continue;
+        }
+
+        file.seek(SeekFrom::Start(len - 32 - skip))?;
+        file.read_exact(&mut footer)?;
+
+        if &footer[0..8] == b"APETAGEX" {
+            footer_at = Some(len - 32 - skip);
+            break;
+        }
+    }
+
+    let Some(footer_at) = footer_at else {
+        return Ok(vec![]);
+    };
+
+    // The size counts the items and the footer, but not any header in front of them
+    let size = read_u32(&footer, 12) as u64;
+    let count = read_u32(&footer, 16);
+
+    if size < 32 || size > footer_at + 32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad APE tag size",
+        ));
+    }
+
+    let mut items = vec![0; size as usize - 32];
+    file.seek(SeekFrom::Start(footer_at + 32 - size))?;
+    file.read_exact(&mut items)?;
+
+    let mut tags = Vec::new();
+    let mut at = 0;
+
+    for _ in 0..count {
+        if at + 8 > items.len() {
+            break;
+        }
+
+        let value_len = read_u32(&items, at) as usize;
+        let flags = read_u32(&items, at + 4);
+        at += 8;
+
+        let Some(key_len) = items[at..].iter().position(|x| *x == 0) else {
+            break;
+        };
+        let key = String::from_utf8_lossy(&items[at..at + key_len]).to_lowercase();
+        at += key_len + 1;
+
+        if at + value_len > items.len() {
+            break;
+        }
+        let item_value = &items[at..at + value_len];
+        at += value_len;
+
+        // Anything but text is binary data or a link
+        if flags & 0b110 != 0 {
+            continue;
+        }
+
+        let name = match key.as_str() {
+            "album artist" | "albumartist" => "album_artist",
+            "track" => "track",
+            "disc" | "discnumber" => "disc",
+            "year" | "date" => "year",
+            name => name,
+        };
+
+        // Multiple values are separated by nulls, we only keep the first
+        let text = String::from_utf8_lossy(item_value);
+        if let Some(tag) = field_tag(name, text.split('\0').next().unwrap_or("")) {
+            tags.push(tag);
+        }
+    }
+
+    Ok(tags)
+}
+
+/// Stream info from the header of a Monkey's Audio file, which symphonia has no reader for
+fn monkeys_audio_info(path: &Path) -> io::Result<StreamInfo> {
+    let mut header = Vec::new();
+    fs::File::open(path)?.take(256).read_to_end(&mut header)?;
+
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, "not a Monkey's Audio file");
+
+    if header.len() < 6 || &header[0..4] != b"MAC " {
+        return Err(bad());
+    }
+
+    // Only the layout from 3.98 on, which is everything made in the last couple decades
+    if read_u16(&header, 4) < 3980 {
+        return Ok(StreamInfo::default());
+    }
+
+    let at = read_u32(&header, 8) as usize;
+
+    if header.len() < at + 24 {
+        return Err(bad());
+    }
+
+    let blocks_per_frame = read_u32(&header, at + 4) as u64;
+    let final_frame_blocks = read_u32(&header, at + 8) as u64;
+    let total_frames = read_u32(&header, at + 12) as u64;
+    let channels = read_u16(&header, at + 18) as u32;
+    let sample_rate = read_u32(&header, at + 20);
+
+    let samples = match total_frames {
+        0 => 0,
+        frames => (frames - 1) * blocks_per_frame + final_frame_blocks,
+    };
+
+    Ok(StreamInfo {
+        duration: Some(samples as f64 / sample_rate as f64).filter(|x| x.is_finite()),
+        sample_rate: Some(sample_rate).filter(|x| *x != 0),
+        channels: Some(channels).filter(|x| *x != 0),
+    })
+}
+
+fn stream_tags(info: &StreamInfo, size: u64) -> TagEntries {
+    let mut tags = Vec::new();
+
+    if let Some(duration) = info.duration.filter(|x| *x > 0.0) {
+        tags.push(autotagger::duration(duration));
+
+        // An average over the whole file, so it counts the tags and cover art too, but they're small next to the audio
+        let bitrate = (size as f64 * 8.0 / duration / 1000.0).round() as i64;
+        tags.push(number("bitrate", &bitrate.to_string()));
+    }
+
+    if let Some(sample_rate) = info.sample_rate {
+        tags.push(number("sample_rate", &sample_rate.to_string()));
+    }
+
+    if let Some(channels) = info.channels {
+        tags.push(number("channels", &channels.to_string()));
+    }
+
+    tags
+}
+
+/// Tags and stream info for WAVE, MP3 and anything else with ID3, FLAC, Ogg Vorbis and Opus, M4A and Monkey's Audio
+pub struct AudioTagger;
+
+impl Autotagger for AudioTagger {
+    fn name(&self) -> &str {
+        "audio"
+    }
+
+    fn applies_to(&self, source: &TagSource) -> bool {
+        source
+            .magic_parts()
+            .any(|x| x.contains(" ID3 ") || x == "WAVE audio")
+            || audio_type(source).is_some()
+    }
+
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String> {
+        let mut tags = Vec::new();
+
+        let id3 = source.magic_parts().any(|x| x.contains(" ID3 "));
+
+        if id3 {
+            tags.push(value(
+                "type",
+                match source.extension() {
+                    Some("mp3") => "mp3",
+                    _ => "audio",
+                },
+            ));
+        }
+
+        if source.magic_parts().any(|x| x == "WAVE audio") {
+            tags.push(value("type", "wav"));
+            tags.push(flag("audio"));
+        }
+
+        let audio_type = audio_type(source);
+
+        if let Some(audio_type) = audio_type {
+            tags.push(value("type", audio_type));
+            tags.push(flag("audio"));
+        }
+
+        let info = if audio_type == Some("ape") {
+            monkeys_audio_info(source.path).map_err(|err| err.to_string())?
+        } else {
+            match symphonia_tags(source) {
+                Ok((mut container_tags, info)) => {
+                    tags.append(&mut container_tags);
+                    info
+                }
+                // For WAVE and ID3 files the stream info is a bonus, symphonia can't read every file with ID3 in it
+                Err(err) if audio_type.is_none() => {
+                    warn!("Error reading audio from {:?}: {}", source.path, err);
+                    StreamInfo::default()
+                }
+                Err(err) => return Err(format!("error reading audio: {}", err)),
+            }
+        };
+
+        tags.append(&mut stream_tags(&info, source.metadata.size()));
+
+        // The id3 crate knows more of ID3's quirks than symphonia does, so its tags win
+        let id3_tags = if id3 {
+            id3_tags(source.path)
+        } else {
+            Ok(vec![])
+        };
+
+        let ape_tags =
+            ape_tags(source.path).map_err(|err| format!("error reading APE tag: {}", err));
+
+        // A broken tag doesn't make what was read before it wrong, so keep that and mark the point
+        for extra_tags in [id3_tags, ape_tags] {
+            match extra_tags {
+                Ok(mut extra_tags) => tags.append(&mut extra_tags),
+                Err(err) => {
+                    warn!("Error reading tags from {:?}: {}", source.path, err);
+                    tags.push(value(autotagger::ERROR_TAG, self.name()));
+                }
+            }
+        }
+
+        Ok(last_per_name(tags))
+    }
+}
@@ -1,262 +1,522 @@
-use std::collections::HashMap;
-
+use std::collections::{HashMap, HashSet};
+
+use crate::archive_tagger::ArchiveTagger;
+use crate::audio_tagger::AudioTagger;
+use crate::command_tagger::CommandTagger;
+use crate::document_tagger::DocumentTagger;
+use crate::exif_tagger::ExifTagger;
+use crate::tag_rules::RuleTagger;
 use crate::utils::*;
+use crate::video_tagger::VideoTagger;
 use magic::{Cookie, CookieFlags};
 use std::fs;
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
 
-use id3::TagLike;
+/// What taggers get to look at for a file, so libmagic only has to run once for all of them
+pub struct TagSource<'a> {
+    pub path: &'a Path,
+    pub metadata: fs::Metadata,
+    pub magic: String,
+}
+
+impl TagSource<'_> {
+    /// The comma separated parts of the libmagic description
+    pub fn magic_parts(&self) -> std::str::Split<'_, &str> {
+        self.magic.split(", ")
+    }
+
+    pub fn extension(&self) -> Option<&str> {
+        self.path.extension().and_then(|x| x.to_str())
+    }
+}
+
+pub trait Autotagger: Send + Sync {
+    /// What it's called in the `autotaggers` config
+    fn name(&self) -> &str;
+
+    fn applies_to(&self, source: &TagSource) -> bool;
+
+    /// The tags for a file, or why it couldn't be read, which doesn't stop the other taggers
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String>;
+
+    /// Whether it runs when the config doesn't say either way
+    fn enabled_by_default(&self) -> bool {
+        true
+    }
+}
+
+/// The longest file name most filesystems allow, which tags have to fit in as `name = value`
+const NAME_MAX: usize = 255;
+
+/// What a point is tagged with when an autotagger fails on it, valued with the tagger's name
+pub const ERROR_TAG: &str = "ffs:error";
+
+/// What each tagger made of a file, by tagger name
+pub type TaggerResults = Vec<(String, Result<TagEntries, String>)>;
+
+/// Keeps only the last value for each tag name, for taggers that refine what they found as they go
+pub fn last_per_name(tags: TagEntries) -> TagEntries {
+    let mut seen = HashSet::new();
 
-pub fn get_generic_tags_from_file(path: &Path, magic_file: &str) -> TagEntries {
-    let cookie = Cookie::open(CookieFlags::default()).unwrap();
-    cookie.load(&[magic_file]).expect("error loading magic");
+    let mut tags = tags
+        .into_iter()
+        .rev()
+        .filter(|(tag_name, _)| seen.insert(tag_name.clone()))
+        .collect::<TagEntries>();
 
-    let magic_data = cookie.file(path).unwrap();
+    tags.reverse();
+    tags
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|x| x.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_default();
+
+    format!("panicked: {}", message)
+}
 
-    // let mut tag_map: HashMap<String, (Option<String>, Option<i64>)> = HashMap::new();
-    let mut tag_map: HashMap<String, Option<(String, Option<i64>)>> = HashMap::new();
+/// Free text from a file as a tag that works as a directory name, which `/`, control characters and overly long text would break
+pub fn text_tag(name: &str, text: &str) -> Option<TagEntry> {
+    let mut text = text
+        .replace('/', "-")
+        .replace(|x: char| x.is_control(), " ");
+
+    let mut end = NAME_MAX.saturating_sub(name.len() + " = ".len());
+    if end < text.len() {
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        text.truncate(end);
+    }
 
-    let mut is_image = false;
+    let text = text.trim();
 
-    for (i, magic_str) in magic_data.split(", ").enumerate() {
-        if i == 0 {
-            tag_map.insert(
+    if text.is_empty() {
+        None
+    } else {
+        Some(value(name, text))
+    }
+}
+
+pub fn flag(name: &str) -> TagEntry {
+    (name.to_string(), None)
+}
+
+pub fn value(name: &str, value: &str) -> TagEntry {
+    (name.to_string(), Some((value.to_string(), None)))
+}
+
+pub fn number(name: &str, value: &str) -> TagEntry {
+    (
+        name.to_string(),
+        Some((value.to_string(), value.parse::<i64>().ok())),
+    )
+}
+
+/// A `duration` written like `3:05` or `1:02:03`, sorting by its seconds
+pub fn duration(seconds: f64) -> TagEntry {
+    let seconds = seconds.round() as i64;
+
+    let text = if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    };
+
+    ("duration".to_string(), Some((text, Some(seconds))))
+}
+
+/// A `date` from its parts, written like `2022-07-18 14:03:00`
+pub fn date(year: i64, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> TagEntry {
+    let text = format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, hour, minute, second
+    );
+
+    // The digits alone sort the same way the date does
+    let sort_value = text
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>()
+        .parse::<i64>()
+        .ok();
+
+    ("date".to_string(), Some((text, sort_value)))
+}
+
+/// Gives every file a `type` from the first word libmagic has for it, and the whole description as `magic`
+struct MagicTagger;
+
+impl Autotagger for MagicTagger {
+    fn name(&self) -> &str {
+        "magic"
+    }
+
+    fn applies_to(&self, _source: &TagSource) -> bool {
+        true
+    }
+
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String> {
+        let mut tags = vec![value("magic", &source.magic)];
+
+        if let Some(first) = source.magic_parts().next() {
+            tags.push((
                 "type".to_string(),
-                magic_str
+                first
                     .split_whitespace()
                     .next()
                     .map(|x| (x.to_lowercase(), None)),
-            );
-
-            if magic_str == "PNG image data" || magic_str == "JPEG image data" {
-                is_image = true;
-            }
+            ));
         }
 
-        if is_image {
-            let resolution_split = magic_str.split('x').collect::<Vec<&str>>();
+        Ok(tags)
+    }
+}
 
-            if let [width, height] = &resolution_split[..] {
-                let width = width.trim();
-                let height = height.trim();
+struct ImageTagger;
 
-                let res = format!("{}x{}", width, height);
-
-                tag_map.insert("resolution".to_string(), Some((res, None)));
-                tag_map.insert(
-                    "width".to_string(),
-                    Some((width.to_string(), width.parse::<i64>().ok())),
-                );
-                tag_map.insert(
-                    "height".to_string(),
-                    Some((height.to_string(), height.parse::<i64>().ok())),
-                );
-            }
+impl Autotagger for ImageTagger {
+    fn name(&self) -> &str {
+        "image"
+    }
 
-            let metadata_split = magic_str.split('=').collect::<Vec<&str>>();
+    fn applies_to(&self, source: &TagSource) -> bool {
+        matches!(
+            source.magic_parts().next(),
+            Some("PNG image data") | Some("JPEG image data")
+        )
+    }
 
-            match &metadata_split[..] {
-                ["manufacturer", manufacturer] => {
-                    tag_map.insert(
-                        "camera_manufacturer".to_string(),
-                        Some(((*manufacturer).to_string(), None)),
-                    );
-                }
-                ["software", software] => {
-                    tag_map.insert(
-                        "camera_software".to_string(),
-                        Some(((*software).to_string(), None)),
-                    );
-                }
-                ["model", model] => {
-                    tag_map.insert(
-                        "camera_model".to_string(),
-                        Some(((*model).to_string(), None)),
-                    );
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String> {
+        let mut tags = Vec::new();
+
+        for magic_str in source.magic_parts() {
+            if let [width, height] = &magic_str.split('x').collect::<Vec<&str>>()[..] {
+                let width = width.trim();
+                let height = height.trim();
+
+                // Other parts have x's in them too, like "density 72x72"
+                if width.parse::<u32>().is_err() || height.parse::<u32>().is_err() {
+                    continue;
                 }
-                _ => {}
+
+                tags.push(value("resolution", &format!("{}x{}", width, height)));
+                tags.push(number("width", width));
+                tags.push(number("height", height));
             }
         }
 
-        if magic_str == "directory" {
-            if fs::metadata(path.to_str().expect("gotta b unicode bby").to_string() + "/.git")
-                .is_ok()
-            {
-                tag_map.insert("code".to_string(), None);
-                tag_map.insert("git".to_string(), None);
-            }
+        Ok(last_per_name(tags))
+    }
+}
 
-            if fs::metadata(
-                path.to_str().expect("gotta b unicode bby").to_string() + "/package.json",
-            )
-            .is_ok()
-            {
-                tag_map.insert("code".to_string(), None);
-                tag_map.insert(
-                    "language".to_string(),
-                    Some(("javascript".to_string(), None)),
-                );
-                tag_map.insert("npm".to_string(), None);
-            }
+/// Recognises project dirs by the files at their top
+struct ProjectTagger;
 
-            if fs::metadata(path.to_str().expect("gotta b unicode bby").to_string() + "/Cargo.toml")
-                .is_ok()
-            {
-                tag_map.insert("code".to_string(), None);
-                tag_map.insert("language".to_string(), Some(("rust".to_string(), None)));
-                tag_map.insert("cargo".to_string(), None);
-            }
+impl Autotagger for ProjectTagger {
+    fn name(&self) -> &str {
+        "project"
+    }
 
-            if fs::metadata(path.to_str().expect("gotta b unicode bby").to_string() + "/elm.json")
-                .is_ok()
-            {
-                tag_map.insert("code".to_string(), None);
-                tag_map.insert("language".to_string(), Some(("elm".to_string(), None)));
-                tag_map.insert("elm".to_string(), None);
-            }
-        }
+    fn applies_to(&self, source: &TagSource) -> bool {
+        source.metadata.is_dir()
+    }
 
-        if magic_str == "ASCII text" {
-            tag_map.insert("ascii".to_string(), None);
-        }
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String> {
+        let mut tags = Vec::new();
+        let has = |name: &str| fs::metadata(source.path.join(name)).is_ok();
 
-        if magic_str == "C source" {
-            tag_map.insert("code".to_string(), None);
-            tag_map.insert("language".to_string(), Some(("c".to_string(), None)));
+        if has(".git") {
+            tags.push(flag("code"));
+            tags.push(flag("git"));
         }
 
-        if magic_str == "C source" || magic_data == "ASCII text" {
-            tag_map.insert("text".to_string(), None);
-
-            match path.extension().map(|x| x.to_str().expect("unicode bitch")) {
-                Some("rs") => {
-                    tag_map.insert("code".to_string(), None);
-                    tag_map.insert("language".to_string(), Some(("rust".to_string(), None)));
-                }
-                Some("js") => {
-                    tag_map.insert("code".to_string(), None);
-                    tag_map.insert(
-                        "language".to_string(),
-                        Some(("javascript".to_string(), None)),
-                    );
-                }
-                Some("elm") => {
-                    tag_map.insert("code".to_string(), None);
-                    tag_map.insert("language".to_string(), Some(("elm".to_string(), None)));
-                }
-                Some("json") => {
-                    tag_map.insert("language".to_string(), Some(("json".to_string(), None)));
-                }
-                Some("toml") => {
-                    tag_map.insert("language".to_string(), Some(("toml".to_string(), None)));
-                }
-                Some("nix") => {
-                    tag_map.insert("language".to_string(), Some(("nix".to_string(), None)));
-                }
-                Some("ini") => {
-                    tag_map.insert("language".to_string(), Some(("ini".to_string(), None)));
-                }
-                _ => {}
-            }
-        }
-        if magic_str == "Python script" {
-            tag_map.insert("code".to_string(), None);
-            tag_map.insert("language".to_string(), Some(("python".to_string(), None)));
+        if has("package.json") {
+            tags.push(flag("code"));
+            tags.push(value("language", "javascript"));
+            tags.push(flag("npm"));
         }
 
-        if magic_str == "dynamically linked" {
-            tag_map.insert("linker".to_string(), Some(("dynamic".to_string(), None)));
+        if has("Cargo.toml") {
+            tags.push(flag("code"));
+            tags.push(value("language", "rust"));
+            tags.push(flag("cargo"));
         }
 
-        if magic_str.starts_with("ELF 64-bit") {
-            tag_map.insert("elf".to_string(), None);
-            tag_map.insert("arch".to_string(), Some(("x86_64".to_string(), None)));
+        if has("elm.json") {
+            tags.push(flag("code"));
+            tags.push(value("language", "elm"));
+            tags.push(flag("elm"));
         }
 
-        if magic_str.starts_with("ELF 32-bit") {
-            tag_map.insert("elf".to_string(), None);
-            tag_map.insert("arch".to_string(), Some(("i686".to_string(), None)));
-        }
+        Ok(last_per_name(tags))
+    }
+}
 
-        if magic_str == "Zip archive data" {
-            tag_map.insert("archive".to_string(), None);
-        }
+/// Text files and source code, going by libmagic and then by extension
+struct CodeTagger;
 
-        if magic_str.starts_with("MP4 ") {
-            tag_map.insert("type".to_string(), Some(("mp4".to_string(), None)));
-            tag_map.insert("video".to_string(), None);
-        }
+impl Autotagger for CodeTagger {
+    fn name(&self) -> &str {
+        "code"
+    }
 
-        if magic_str.contains(" ID3 ") {
-            tag_map.insert(
-                "type".to_string(),
-                Some((
-                    match path.extension().map(|x| x.to_str().expect("unicode bitch")) {
-                        Some("mp3") => "mp3".to_string(),
-                        _ => "audio".to_string(),
-                    },
-                    None,
-                )),
-            );
-
-            let id3_tag = id3::Tag::read_from_path(path).expect("Error reading ID3 tag");
-
-            if let Some(album) = id3_tag.album() {
-                tag_map.insert("album".to_string(), Some((album.to_string(), None)));
+    fn applies_to(&self, source: &TagSource) -> bool {
+        source
+            .magic_parts()
+            .any(|x| x == "ASCII text" || x == "C source" || x == "Python script")
+    }
+
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String> {
+        let mut tags = Vec::new();
+
+        for magic_str in source.magic_parts() {
+            if magic_str == "ASCII text" {
+                tags.push(flag("ascii"));
+            }
+
+            if magic_str == "C source" {
+                tags.push(flag("code"));
+                tags.push(value("language", "c"));
             }
 
-            if let Some(artist) = id3_tag.artist() {
-                tag_map.insert("artist".to_string(), Some((artist.to_string(), None)));
+            if magic_str == "C source" || source.magic == "ASCII text" {
+                tags.push(flag("text"));
+
+                match source.extension() {
+                    Some("rs") => {
+                        tags.push(flag("code"));
+                        tags.push(value("language", "rust"));
+                    }
+                    Some("js") => {
+                        tags.push(flag("code"));
+                        tags.push(value("language", "javascript"));
+                    }
+                    Some("elm") => {
+                        tags.push(flag("code"));
+                        tags.push(value("language", "elm"));
+                    }
+                    Some(language @ ("json" | "toml" | "nix" | "ini")) => {
+                        tags.push(value("language", language));
+                    }
+                    _ => {}
+                }
             }
 
-            if let Some(album_artist) = id3_tag.album_artist() {
-                tag_map.insert(
-                    "album_artist".to_string(),
-                    Some((album_artist.to_string(), None)),
-                );
+            if magic_str == "Python script" {
+                tags.push(flag("code"));
+                tags.push(value("language", "python"));
             }
+        }
+
+        Ok(last_per_name(tags))
+    }
+}
 
-            // if let Some(title) = id3_tag.title() {
-            //     tag_map.insert("title".to_string(), Some((title.to_string(), None)));
-            // }
+struct ElfTagger;
 
-            if let Some(genre) = id3_tag.genre() {
-                tag_map.insert("genre".to_string(), Some((genre.to_string(), None)));
+impl Autotagger for ElfTagger {
+    fn name(&self) -> &str {
+        "elf"
+    }
+
+    fn applies_to(&self, source: &TagSource) -> bool {
+        source.magic.starts_with("ELF ")
+    }
+
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String> {
+        let mut tags = vec![flag("elf")];
+
+        for magic_str in source.magic_parts() {
+            if magic_str == "dynamically linked" {
+                tags.push(value("linker", "dynamic"));
             }
 
-            if let Some(year) = id3_tag.year() {
-                tag_map.insert(
-                    "year".to_string(),
-                    Some((year.to_string(), Some(year as i64))),
-                );
+            if magic_str.starts_with("ELF 64-bit") {
+                tags.push(value("arch", "x86_64"));
             }
 
-            let mut comments = id3_tag.comments();
-            if let Some(comment) = comments.next() {
-                tag_map.insert("comment".to_string(), Some((comment.text.clone(), None)));
+            if magic_str.starts_with("ELF 32-bit") {
+                tags.push(value("arch", "i686"));
             }
         }
 
-        if magic_str == "WAVE audio" {
-            tag_map.insert("type".to_string(), Some(("wav".to_string(), None)));
-            tag_map.insert("audio".to_string(), None);
+        Ok(tags)
+    }
+}
+
+/// The taggers points get run through, in order, with later ones overriding the tags of earlier ones
+pub struct Autotaggers {
+    magic_file: String,
+    enabled: HashMap<String, bool>,
+    taggers: Vec<Box<dyn Autotagger>>,
+}
+
+impl Autotaggers {
+    /// No taggers at all besides the ones that get registered, `enabled` being the `autotaggers` config
+    pub fn new(magic_file: &str, enabled: HashMap<String, bool>) -> Autotaggers {
+        Autotaggers {
+            magic_file: magic_file.to_string(),
+            enabled,
+            taggers: Vec::new(),
         }
     }
 
-    tag_map.insert("magic".to_string(), Some((magic_data, None)));
+    pub fn with_builtin(magic_file: &str, enabled: HashMap<String, bool>) -> Autotaggers {
+        let mut autotaggers = Autotaggers::new(magic_file, enabled);
+
+        autotaggers.register(Box::new(MagicTagger));
+        autotaggers.register(Box::new(ImageTagger));
+        autotaggers.register(Box::new(ExifTagger));
+        autotaggers.register(Box::new(ProjectTagger));
+        autotaggers.register(Box::new(CodeTagger));
+        autotaggers.register(Box::new(ElfTagger));
+        autotaggers.register(Box::new(ArchiveTagger));
+        autotaggers.register(Box::new(DocumentTagger));
+        autotaggers.register(Box::new(VideoTagger));
+        autotaggers.register(Box::new(AudioTagger));
+
+        autotaggers
+    }
+
+    pub fn from_config(cfg: &crate::FfsConfig) -> Autotaggers {
+        let mut autotaggers = Autotaggers::with_builtin(&cfg.magic_file, cfg.autotaggers.clone());
 
-    let mut tags: TagEntries = Vec::new();
+        autotaggers.register(Box::new(RuleTagger::new(&cfg.tag_rules)));
 
-    for (tag_name, tag_content) in tag_map.into_iter() {
-        let tag_content_sanitised = tag_content.map(|(tag_value, maybe_tag_sort_value)| {
-            (
-                tag_value.trim_matches(char::from(0)).to_string(),
-                maybe_tag_sort_value,
-            )
-        });
+        for command in &cfg.tag_commands {
+            autotaggers.register(Box::new(CommandTagger::new(command)));
+        }
 
-        tags.push((tag_name, tag_content_sanitised));
+        autotaggers
     }
 
-    tags
+    pub fn register(&mut self, tagger: Box<dyn Autotagger>) {
+        if self.taggers.iter().any(|x| x.name() == tagger.name()) {
+            panic!("there's already an autotagger called {:?}", tagger.name());
+        }
+
+        self.taggers.push(tagger);
+    }
+
+    fn is_enabled(&self, tagger: &dyn Autotagger) -> bool {
+        self.enabled
+            .get(tagger.name())
+            .copied()
+            .unwrap_or_else(|| tagger.enabled_by_default())
+    }
+
+    fn magic(&self, path: &Path) -> Result<String, String> {
+        let cookie = Cookie::open(CookieFlags::default()).map_err(|err| err.to_string())?;
+        cookie
+            .load(&[&self.magic_file])
+            .map_err(|err| format!("error loading magic: {}", err))?;
+
+        // The magic crate mangles paths that aren't UTF-8, so those go through an open fd instead
+        match path.to_str() {
+            Some(_) => cookie.file(path),
+            None => {
+                let file = fs::File::open(path).map_err(|err| err.to_string())?;
+                cookie.set_flags(magic::flags::SYMLINK);
+                cookie.file(format!("/proc/self/fd/{}", file.as_raw_fd()))
+            }
+        }
+        .map_err(|err| err.to_string())
+    }
+
+    /// The tags each enabled tagger gives a file, by tagger name, empty for the ones that don't apply to it
+    ///
+    /// Like with a single set of tags, a tag from a later tagger replaces any of the same name from earlier ones.
+    /// A tagger that fails has the error instead, and when the file can't be read at all every tagger does
+    pub fn tags(&self, path: &Path) -> TaggerResults {
+        let enabled = self.taggers.iter().filter(|x| self.is_enabled(x.as_ref()));
+
+        let source = fs::metadata(path)
+            .map_err(|err| err.to_string())
+            .and_then(|metadata| {
+                Ok(TagSource {
+                    path,
+                    metadata,
+                    magic: self.magic(path)?,
+                })
+            });
+
+        let source = match source {
+            Ok(source) => source,
+            Err(err) => {
+                error!("Error reading {:?} for autotagging: {}", path, err);
+
+                return enabled
+                    .map(|tagger| (tagger.name().to_string(), Err(err.clone())))
+                    .collect();
+            }
+        };
+
+        let mut tagged = enabled
+            .map(|tagger| {
+                // The file parsers taggers use can panic on files crafted or broken in just the right way
+                let tags = if tagger.applies_to(&source) {
+                    panic::catch_unwind(AssertUnwindSafe(|| tagger.tags(&source)))
+                        .unwrap_or_else(|panic| Err(panic_message(panic)))
+                } else {
+                    Ok(vec![])
+                };
+
+                if let Err(err) = &tags {
+                    error!(
+                        "Autotagger {:?} failed on {:?}: {}",
+                        tagger.name(),
+                        path,
+                        err
+                    );
+                }
+
+                (tagger.name().to_string(), tags)
+            })
+            .collect::<Vec<(String, Result<TagEntries, String>)>>();
+
+        let mut seen = HashSet::new();
+
+        for tags in tagged.iter_mut().rev().filter_map(|(_, x)| x.as_mut().ok()) {
+            // Names can have several values from the same tagger, like keywords, but not across taggers
+            let names = tags
+                .iter()
+                .map(|(tag_name, _)| tag_name.clone())
+                .collect::<HashSet<String>>();
+
+            let mut added = HashSet::new();
+
+            *tags = tags
+                .drain(..)
+                .filter(|(tag_name, _)| !seen.contains(tag_name))
+                .filter(|x| added.insert(x.clone()))
+                .map(|(tag_name, tag_content)| {
+                    let tag_content_sanitised =
+                        tag_content.map(|(tag_value, maybe_tag_sort_value)| {
+                            (
+                                tag_value.trim_matches(char::from(0)).to_string(),
+                                maybe_tag_sort_value,
+                            )
+                        });
+
+                    (tag_name, tag_content_sanitised)
+                })
+                .collect();
+
+            seen.extend(names);
+        }
+
+        tagged
+    }
 }
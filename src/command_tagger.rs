@@ -0,0 +1,150 @@
+use crate::autotagger::{Autotagger, TagSource};
+use crate::tag_rules::{FileMatch, FileMatchConfig};
+use crate::utils::*;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A `tag_commands` entry in the config, an executable that gets the path of a file as its last argument and prints tags for it
+///
+/// Its output is either JSON, an object of tag names to values or `null`, or lines written like the `add` command's tags
+#[derive(Deserialize, Debug, Clone)]
+pub struct TagCommandConfig {
+    /// What it's called in the `autotaggers` config
+    name: String,
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    /// Seconds to give it before it's killed
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    #[serde(flatten)]
+    matching: FileMatchConfig,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+pub struct CommandTagger {
+    name: String,
+    command: String,
+    args: Vec<String>,
+    timeout: Duration,
+    matching: FileMatch,
+}
+
+impl CommandTagger {
+    pub fn new(cfg: &TagCommandConfig) -> CommandTagger {
+        CommandTagger {
+            name: cfg.name.clone(),
+            command: cfg.command.clone(),
+            args: cfg.args.clone(),
+            timeout: Duration::from_secs(cfg.timeout),
+            matching: FileMatch::from_config(&cfg.matching)
+                .unwrap_or_else(|err| panic!("tag command {:?} is invalid: {}", cfg.name, err)),
+        }
+    }
+
+    fn run(&self, source: &TagSource) -> Result<String, String> {
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .arg(source.path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            // Its own process group, so whatever it starts can be killed along with it
+            .process_group(0)
+            .spawn()
+            .map_err(|err| format!("couldn't run {:?}: {}", self.command, err))?;
+
+        // Read on the side, a command printing more than the pipe holds would never exit otherwise
+        let mut stdout = child.stdout.take().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut out = String::new();
+            let _ = tx.send(stdout.read_to_string(&mut out).map(|_| out));
+        });
+
+        let started = Instant::now();
+
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(|err| err.to_string())? {
+                break status;
+            }
+
+            if started.elapsed() > self.timeout {
+                unsafe {
+                    libc::kill(-(child.id() as i32), libc::SIGKILL);
+                }
+                let _ = child.wait();
+                return Err(format!("timed out after {:?}", self.timeout));
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        // Anything it left running could hold stdout open forever, what it already printed stays in the pipe
+        unsafe {
+            libc::kill(-(child.id() as i32), libc::SIGKILL);
+        }
+
+        // Something that left the process group could still have it open, so only wait out the rest of the timeout
+        let out = rx
+            .recv_timeout(self.timeout.saturating_sub(started.elapsed()))
+            .map_err(|_| format!("output still open after {:?}", self.timeout))?
+            .map_err(|err| format!("bad output: {}", err))?;
+
+        if !status.success() {
+            return Err(format!("exited with {}", status));
+        }
+
+        Ok(out)
+    }
+}
+
+fn json_to_tag((name, value): (String, serde_json::Value)) -> Result<TagEntry, String> {
+    use serde_json::Value;
+
+    let content = match value {
+        Value::Null => None,
+        Value::String(value) => Some((value.clone(), value.parse::<i64>().ok())),
+        Value::Number(number) => Some((number.to_string(), number.as_i64())),
+        Value::Bool(value) => Some((value.to_string(), None)),
+        _ => return Err(format!("bad value for {:?}", name)),
+    };
+
+    Ok((name, content))
+}
+
+fn parse_output(out: &str) -> Result<TagEntries, String> {
+    if out.trim_start().starts_with('{') {
+        let object = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(out)
+            .map_err(|err| format!("bad JSON: {}", err))?;
+
+        object.into_iter().map(json_to_tag).collect()
+    } else {
+        Ok(out
+            .lines()
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(parse_tag_arg)
+            .collect())
+    }
+}
+
+impl Autotagger for CommandTagger {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn applies_to(&self, source: &TagSource) -> bool {
+        self.matching.matches(source)
+    }
+
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String> {
+        self.run(source).and_then(|x| parse_output(&x))
+    }
+}
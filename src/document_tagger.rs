@@ -0,0 +1,402 @@
+use crate::autotagger::{self, flag, number, text_tag, value, Autotagger, TagSource};
+use crate::utils::*;
+use lopdf::Object;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+/// Metadata files are small, anything past this in one isn't worth reading
+const ENTRY_LIMIT: u64 = 16 * 1024 * 1024;
+
+/// PDFs get parsed whole into memory, past this they're likely scans that aren't worth it
+const PDF_LIMIT: u64 = 64 * 1024 * 1024;
+
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+const DCTERMS_NS: &str = "http://purl.org/dc/terms/";
+const XMP_NS: &str = "http://ns.adobe.com/xap/1.0/";
+const CONTAINER_NS: &str = "urn:oasis:names:tc:opendocument:xmlns:container";
+const ODF_META_NS: &str = "urn:oasis:names:tc:opendocument:xmlns:meta:1.0";
+const OOXML_APP_NS: &str =
+    "http://schemas.openxmlformats.org/officeDocument/2006/extended-properties";
+
+/// What a document says about itself, whichever format it's in
+#[derive(Default)]
+struct DocumentInfo {
+    kind: &'static str,
+    title: Option<String>,
+    authors: Vec<String>,
+    publisher: Option<String>,
+    language: Option<String>,
+    pages: Option<i64>,
+    slides: Option<i64>,
+    /// As the document has it, which is a PDF date or some form of ISO 8601
+    created: Option<String>,
+}
+
+impl DocumentInfo {
+    fn new(kind: &'static str) -> DocumentInfo {
+        DocumentInfo {
+            kind,
+            ..Default::default()
+        }
+    }
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    Some(text.trim().to_string()).filter(|x| !x.is_empty())
+}
+
+/// The values of an element or attribute wherever it is, `rdf:li`s count as separate values like in XMP
+fn xml_values(doc: &roxmltree::Document, name: (&str, &str)) -> Vec<String> {
+    let mut values = Vec::new();
+
+    for node in doc.descendants() {
+        if node.has_tag_name(name) {
+            let items = node
+                .descendants()
+                .filter(|x| x.has_tag_name("li"))
+                .filter_map(|x| x.text().and_then(non_empty))
+                .collect::<Vec<String>>();
+
+            if items.is_empty() {
+                values.extend(non_empty(
+                    &node
+                        .descendants()
+                        .filter(|x| x.is_text())
+                        .filter_map(|x| x.text())
+                        .collect::<String>(),
+                ));
+            } else {
+                values.extend(items);
+            }
+        }
+
+        if let Some(attribute) = node.attribute(name).and_then(non_empty) {
+            values.push(attribute);
+        }
+    }
+
+    values
+}
+
+fn xml_value(doc: &roxmltree::Document, name: (&str, &str)) -> Option<String> {
+    xml_values(doc, name).into_iter().next()
+}
+
+/// A `date` from a PDF date like `D:20200102030405+01'00'` or an ISO 8601 one, in the time it was written in
+fn date_tag(text: &str) -> Option<TagEntry> {
+    let mut digits = Vec::new();
+
+    for c in text.trim().trim_start_matches("D:").chars() {
+        match c {
+            '0'..='9' => digits.push(c.to_digit(10).unwrap()),
+            // The time zone, but only past the date, which can have dashes of its own
+            '+' | 'Z' | 'z' => break,
+            '-' if digits.len() >= 8 => break,
+            _ => {}
+        }
+
+        if digits.len() == 14 {
+            break;
+        }
+    }
+
+    let part = |from: usize, to: usize, default: u32| {
+        digits
+            .get(from..to)
+            .map(|x| x.iter().fold(0, |n, d| n * 10 + d))
+            .unwrap_or(default)
+    };
+
+    if digits.len() < 4 {
+        return None;
+    }
+
+    let (year, month, day) = (part(0, 4, 0), part(4, 6, 1), part(6, 8, 1));
+    let (hour, minute, second) = (part(8, 10, 0), part(10, 12, 0), part(12, 14, 0));
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+
+    Some(autotagger::date(
+        year as i64,
+        month,
+        day,
+        hour,
+        minute,
+        second.min(59),
+    ))
+}
+
+fn pdf_string(doc: &lopdf::Document, object: &Object) -> Option<String> {
+    let (_, object) = doc.dereference(object).ok()?;
+    lopdf::decode_text_string(object)
+        .ok()
+        .and_then(|x| non_empty(&x))
+}
+
+fn pdf_info(path: &Path) -> Result<DocumentInfo, String> {
+    if fs::metadata(path).map_err(|err| err.to_string())?.len() > PDF_LIMIT {
+        return Ok(DocumentInfo::new("pdf"));
+    }
+
+    let mut doc = lopdf::Document::load(path).map_err(|err| err.to_string())?;
+
+    let mut info = DocumentInfo::new("pdf");
+    info.pages = Some(doc.get_pages().len() as i64).filter(|x| *x != 0);
+
+    // Most encrypted PDFs only have an owner password, the strings in them can still be read with an empty one
+    if doc.is_encrypted() && doc.decrypt("").is_err() {
+        return Ok(info);
+    }
+
+    if let Ok(info_dict) = doc
+        .trailer
+        .get(b"Info")
+        .and_then(|x| doc.dereference(x))
+        .and_then(|(_, x)| x.as_dict())
+    {
+        let field = |key: &[u8]| info_dict.get(key).ok().and_then(|x| pdf_string(&doc, x));
+
+        info.title = field(b"Title");
+        info.authors.extend(field(b"Author"));
+        info.created = field(b"CreationDate");
+    }
+
+    let Ok(catalog) = doc.catalog() else {
+        return Ok(info);
+    };
+
+    info.language = catalog.get(b"Lang").ok().and_then(|x| pdf_string(&doc, x));
+
+    // The XMP fills in whatever the info dictionary doesn't have, publishers in particular
+    let xmp = catalog
+        .get(b"Metadata")
+        .and_then(|x| doc.dereference(x))
+        .and_then(|(_, x)| x.as_stream())
+        .and_then(|x| x.decompressed_content().or_else(|_| Ok(x.content.clone())));
+
+    if let Ok(xmp) = xmp {
+        let xmp = String::from_utf8_lossy(&xmp).to_string();
+
+        if let Ok(xmp) = roxmltree::Document::parse(xmp.trim_start_matches('\u{feff}')) {
+            info.title = info.title.or_else(|| xml_value(&xmp, (DC_NS, "title")));
+
+            if info.authors.is_empty() {
+                info.authors = xml_values(&xmp, (DC_NS, "creator"));
+            }
+
+            info.publisher = xml_value(&xmp, (DC_NS, "publisher"));
+            info.language = info
+                .language
+                .or_else(|| xml_value(&xmp, (DC_NS, "language")));
+            info.created = info
+                .created
+                .or_else(|| xml_value(&xmp, (XMP_NS, "CreateDate")));
+        }
+    }
+
+    Ok(info)
+}
+
+struct ZipDocument {
+    archive: zip::ZipArchive<fs::File>,
+}
+
+impl ZipDocument {
+    fn open(path: &Path) -> Result<ZipDocument, String> {
+        let file = fs::File::open(path).map_err(|err| err.to_string())?;
+        let archive = zip::ZipArchive::new(file).map_err(|err| err.to_string())?;
+
+        Ok(ZipDocument { archive })
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.archive.file_names().any(|x| x == name)
+    }
+
+    fn read(&mut self, name: &str) -> Result<Option<String>, String> {
+        let entry = match self.archive.by_name(name) {
+            Ok(entry) => entry,
+            Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+            Err(err) => return Err(format!("error reading {}: {}", name, err)),
+        };
+
+        let mut data = Vec::new();
+        entry
+            .take(ENTRY_LIMIT)
+            .read_to_end(&mut data)
+            .map_err(|err| format!("error reading {}: {}", name, err))?;
+
+        Ok(Some(String::from_utf8_lossy(&data).to_string()))
+    }
+
+    /// An XML file in the archive, parsed and handed to `f`
+    fn with_xml<T>(
+        &mut self,
+        name: &str,
+        f: impl FnOnce(&roxmltree::Document) -> T,
+    ) -> Result<Option<T>, String> {
+        let Some(text) = self.read(name)? else {
+            return Ok(None);
+        };
+
+        let doc = roxmltree::Document::parse(text.trim_start_matches('\u{feff}'))
+            .map_err(|err| format!("error parsing {}: {}", name, err))?;
+
+        Ok(Some(f(&doc)))
+    }
+}
+
+fn epub_info(zip: &mut ZipDocument) -> Result<DocumentInfo, String> {
+    let opf_path = zip
+        .with_xml("META-INF/container.xml", |doc| {
+            doc.descendants()
+                .find(|x| x.has_tag_name((CONTAINER_NS, "rootfile")))
+                .and_then(|x| x.attribute("full-path"))
+                .map(|x| x.to_string())
+        })?
+        .flatten()
+        .ok_or("no rootfile in META-INF/container.xml")?;
+
+    let mut info = DocumentInfo::new("epub");
+
+    zip.with_xml(&opf_path, |doc| {
+        info.title = xml_value(doc, (DC_NS, "title"));
+        info.authors = xml_values(doc, (DC_NS, "creator"));
+        info.publisher = xml_value(doc, (DC_NS, "publisher"));
+        info.language = xml_value(doc, (DC_NS, "language"));
+        info.created = xml_value(doc, (DC_NS, "date"));
+    })?
+    .ok_or_else(|| format!("no {} in the archive", opf_path))?;
+
+    Ok(info)
+}
+
+fn ooxml_info(zip: &mut ZipDocument, kind: &'static str) -> Result<DocumentInfo, String> {
+    let mut info = DocumentInfo::new(kind);
+
+    zip.with_xml("docProps/core.xml", |doc| {
+        info.title = xml_value(doc, (DC_NS, "title"));
+        info.authors = xml_values(doc, (DC_NS, "creator"));
+        info.language = xml_value(doc, (DC_NS, "language"));
+        info.created = xml_value(doc, (DCTERMS_NS, "created"));
+    })?;
+
+    // Word only knows its page count as of the last time it laid the document out, but that's what it shows too
+    zip.with_xml("docProps/app.xml", |doc| {
+        let count = |name| xml_value(doc, (OOXML_APP_NS, name)).and_then(|x| x.parse().ok());
+
+        info.pages = count("Pages").filter(|x| *x != 0);
+        info.slides = count("Slides").filter(|x| *x != 0);
+    })?;
+
+    Ok(info)
+}
+
+fn odf_info(zip: &mut ZipDocument, kind: &'static str) -> Result<DocumentInfo, String> {
+    let mut info = DocumentInfo::new(kind);
+
+    zip.with_xml("meta.xml", |doc| {
+        info.title = xml_value(doc, (DC_NS, "title"));
+        info.authors = xml_values(doc, (ODF_META_NS, "initial-creator"));
+        if info.authors.is_empty() {
+            info.authors = xml_values(doc, (DC_NS, "creator"));
+        }
+        info.language = xml_value(doc, (DC_NS, "language"));
+        info.created = xml_value(doc, (ODF_META_NS, "creation-date"));
+        info.pages = xml_value(doc, (ODF_META_NS, "page-count")).and_then(|x| x.parse().ok());
+    })?;
+
+    Ok(info)
+}
+
+fn zip_info(path: &Path) -> Result<DocumentInfo, String> {
+    let mut zip = ZipDocument::open(path)?;
+
+    let mimetype = zip.read("mimetype")?.unwrap_or_default();
+
+    match mimetype.trim() {
+        "application/epub+zip" => epub_info(&mut zip),
+        "application/vnd.oasis.opendocument.text" => odf_info(&mut zip, "odt"),
+        "application/vnd.oasis.opendocument.spreadsheet" => odf_info(&mut zip, "ods"),
+        "application/vnd.oasis.opendocument.presentation" => odf_info(&mut zip, "odp"),
+        _ if zip.has("word/document.xml") => ooxml_info(&mut zip, "docx"),
+        _ if zip.has("xl/workbook.xml") => ooxml_info(&mut zip, "xlsx"),
+        _ if zip.has("ppt/presentation.xml") => ooxml_info(&mut zip, "pptx"),
+        _ if zip.has("META-INF/container.xml") => epub_info(&mut zip),
+        _ => Err("not a document format we can read".to_string()),
+    }
+}
+
+fn info_tags(info: DocumentInfo) -> TagEntries {
+    let mut tags = vec![value("type", info.kind), flag("document")];
+
+    tags.extend(info.title.and_then(|x| text_tag("title", &x)));
+
+    for author in info.authors {
+        tags.extend(text_tag("author", &author));
+    }
+
+    tags.extend(info.publisher.and_then(|x| text_tag("publisher", &x)));
+
+    if let Some(language) = info.language {
+        tags.push(value("language", &language));
+    }
+
+    if let Some(pages) = info.pages {
+        tags.push(number("pages", &pages.to_string()));
+    }
+
+    if let Some(slides) = info.slides {
+        tags.push(number("slides", &slides.to_string()));
+    }
+
+    if let Some(date) = info.created.as_deref().and_then(date_tag) {
+        tags.push(date);
+    }
+
+    tags
+}
+
+/// Titles, authors, publishers, languages, page counts and creation dates of PDFs, EPUBs and Office and OpenDocument files
+pub struct DocumentTagger;
+
+impl Autotagger for DocumentTagger {
+    fn name(&self) -> &str {
+        "document"
+    }
+
+    fn applies_to(&self, source: &TagSource) -> bool {
+        source.magic_parts().any(|x| {
+            x.starts_with("PDF document")
+                || x.starts_with("EPUB document")
+                || x.starts_with("Microsoft Word 2007+")
+                || x.starts_with("Microsoft Excel 2007+")
+                || x.starts_with("Microsoft PowerPoint 2007+")
+                || x.starts_with("OpenDocument ")
+        }) || source
+            .extension()
+            .map(|x| {
+                ["pdf", "epub", "docx", "xlsx", "pptx", "odt", "ods", "odp"]
+                    .contains(&x.to_lowercase().as_str())
+            })
+            .unwrap_or(false)
+    }
+
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String> {
+        let mut start = [0; 4];
+        let read = fs::File::open(source.path)
+            .and_then(|mut x| x.read(&mut start))
+            .map_err(|err| err.to_string())?;
+
+        let info = match &start[..read] {
+            b"%PDF" => pdf_info(source.path).map_err(|err| format!("error reading PDF: {}", err)),
+            b"PK\x03\x04" => zip_info(source.path),
+            _ => Err("not a document format we can read".to_string()),
+        }?;
+
+        Ok(info_tags(info))
+    }
+}
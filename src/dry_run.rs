@@ -0,0 +1,255 @@
+use super::{get_tags_for_point, schema, Point, SqliteConnection, Tag};
+use crate::store::*;
+use crate::utils::*;
+use diesel::prelude::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Something a scan would do to the database
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PlannedChange {
+    NewPoint {
+        name: String,
+        path: String,
+        tags: Vec<String>,
+    },
+    TagsAdded {
+        id: i32,
+        name: String,
+        path: String,
+        tags: Vec<String>,
+    },
+    PathChanged {
+        id: i32,
+        name: String,
+        from: Option<String>,
+        to: String,
+    },
+    ContentsChanged {
+        id: i32,
+        name: String,
+        path: String,
+    },
+    /// Two paths with the same contents, which would end up sharing a point
+    HashConflict {
+        path: String,
+        hash: String,
+        id: Option<i32>,
+        other_path: Option<String>,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct ScanPlan {
+    pub changes: Vec<PlannedChange>,
+    pub malformed: Vec<(PathBuf, String)>,
+}
+
+fn tag_entry_matches(tag: &Tag, (tag_name, tag_content): &TagEntry) -> bool {
+    tag.name == *tag_name && tag.value == tag_content.as_ref().map(|(value, _)| value.clone())
+}
+
+/// Works out what importing an entry would change, going the same way `update_point_by_path` does
+fn plan_entry(
+    connection: &SqliteConnection,
+    entry: PendingEntry,
+    algorithm: HashAlgorithm,
+    seen_hashes: &mut HashMap<String, String>,
+    changes: &mut Vec<PlannedChange>,
+) {
+    use schema::points;
+
+    let hash = match entry.hash(algorithm) {
+        Ok((hash, _)) => hash,
+        Err(err) => {
+            error!("Error hashing store path {:?}: {}", entry.path, err);
+            return;
+        }
+    };
+    let path = path_to_db(&entry.point_path);
+
+    let by_path = points::dsl::points
+        .filter(points::dsl::path.eq(&path))
+        .first::<Point>(connection)
+        .optional()
+        .expect("error searching points");
+
+    let by_hash = points::dsl::points
+        .filter(points::dsl::hash.eq(&hash))
+        .first::<Point>(connection)
+        .optional()
+        .expect("error searching points");
+
+    let seen_at = seen_hashes.insert(hash.clone(), path.clone());
+
+    let point = match (by_path, by_hash) {
+        (Some(point), by_hash) => {
+            if point.hash != hash {
+                changes.push(PlannedChange::ContentsChanged {
+                    id: point.id,
+                    name: point.name.clone(),
+                    path: path.clone(),
+                });
+
+                if let Some(other) = by_hash.filter(|x| x.id != point.id) {
+                    changes.push(PlannedChange::HashConflict {
+                        path: path.clone(),
+                        hash,
+                        id: Some(other.id),
+                        other_path: other.path,
+                    });
+                }
+            }
+
+            point
+        }
+        (None, Some(point)) => {
+            // Something still at the old path means this is a copy rather than the point having moved
+            if let Some(other_path) = seen_at.or_else(|| {
+                point
+                    .path
+                    .clone()
+                    .filter(|x| path_from_db(x).symlink_metadata().is_ok())
+            }) {
+                changes.push(PlannedChange::HashConflict {
+                    path: path.clone(),
+                    hash,
+                    id: Some(point.id),
+                    other_path: Some(other_path),
+                });
+            }
+
+            changes.push(PlannedChange::PathChanged {
+                id: point.id,
+                name: point.name.clone(),
+                from: point.path.clone(),
+                to: path.clone(),
+            });
+
+            point
+        }
+        (None, None) => {
+            match seen_at {
+                Some(other_path) => changes.push(PlannedChange::HashConflict {
+                    path,
+                    hash,
+                    id: None,
+                    other_path: Some(other_path),
+                }),
+                None => changes.push(PlannedChange::NewPoint {
+                    name: entry.name,
+                    path,
+                    tags: entry.tags.iter().map(tag_entry_to_path_part).collect(),
+                }),
+            }
+
+            return;
+        }
+    };
+
+    let existing_tags = get_tags_for_point(connection, &point);
+
+    let new_tags = entry
+        .tags
+        .iter()
+        .filter(|x| !existing_tags.iter().any(|tag| tag_entry_matches(tag, x)))
+        .map(tag_entry_to_path_part)
+        .collect::<Vec<String>>();
+
+    if !new_tags.is_empty() {
+        changes.push(PlannedChange::TagsAdded {
+            id: point.id,
+            name: point.name,
+            path,
+            tags: new_tags,
+        });
+    }
+}
+
+/// Walks the stores like a scan would, collecting what it would change without writing anything
+pub fn plan_stores(
+    connection: &SqliteConnection,
+    store_dirs: &[&StoreDir],
+    full: bool,
+) -> ScanPlan {
+    let mut plan = ScanPlan::default();
+
+    // Nothing is written as we go, so entries sharing contents within the scan have to be caught here
+    let mut seen_hashes = HashMap::new();
+    let algorithm = db_hash_algorithm(connection);
+
+    for store_dir in store_dirs {
+        let mut changes = Vec::new();
+
+        walk_store(connection, store_dir, full, &mut plan.malformed, |entry| {
+            plan_entry(connection, entry, algorithm, &mut seen_hashes, &mut changes)
+        });
+
+        plan.changes.append(&mut changes);
+    }
+
+    plan
+}
+
+pub fn plan_to_json(plan: &ScanPlan) -> serde_json::Value {
+    serde_json::json!({
+        "changes": plan.changes,
+        "malformed": plan
+            .malformed
+            .iter()
+            .map(|(path, err)| serde_json::json!({ "path": path_to_db(path), "error": err }))
+            .collect::<Vec<serde_json::Value>>(),
+    })
+}
+
+fn describe_path(path: Option<&str>) -> String {
+    match path {
+        Some(path) => format!("{:?}", path_from_db(path)),
+        None => "nowhere".to_string(),
+    }
+}
+
+pub fn print_plan(plan: &ScanPlan) {
+    for change in &plan.changes {
+        match change {
+            PlannedChange::NewPoint { name, path, tags } => {
+                println!(
+                    "new point {:?} at {:?}: {}",
+                    name,
+                    path_from_db(path),
+                    tags.join(", ")
+                )
+            }
+            PlannedChange::TagsAdded { id, name, tags, .. } => {
+                println!("{:?} (id {}) gets tags: {}", name, id, tags.join(", "))
+            }
+            PlannedChange::PathChanged { id, name, from, to } => println!(
+                "{:?} (id {}) moves from {} to {:?}",
+                name,
+                id,
+                describe_path(from.as_deref()),
+                path_from_db(to)
+            ),
+            PlannedChange::ContentsChanged { id, name, path } => println!(
+                "{:?} (id {}) changed contents at {:?}",
+                name,
+                id,
+                path_from_db(path)
+            ),
+            PlannedChange::HashConflict {
+                path, other_path, ..
+            } => println!(
+                "{:?} has the same contents as {}, they'd share a point",
+                path_from_db(path),
+                describe_path(other_path.as_deref())
+            ),
+        }
+    }
+
+    print_malformed(&plan.malformed);
+
+    if plan.changes.is_empty() && plan.malformed.is_empty() {
+        println!("Nothing would change");
+    }
+}
@@ -0,0 +1,248 @@
+use crate::autotagger::{self, number, text_tag, value, Autotagger, TagSource};
+use crate::utils::*;
+use exif::{Exif, In, Tag, Value};
+use std::fs;
+use std::io::{self, Read};
+
+/// RAW formats that are TIFF underneath, which libmagic doesn't always recognise as images
+const RAW_EXTENSIONS: [&str; 9] = [
+    "dng", "nef", "nrw", "cr2", "arw", "orf", "rw2", "pef", "srw",
+];
+
+/// XMP packets are near the start of most files, past this they aren't looked for
+const XMP_SEARCH_LIMIT: u64 = 16 * 1024 * 1024;
+
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+const XMP_NS: &str = "http://ns.adobe.com/xap/1.0/";
+
+/// Camera metadata from the EXIF and XMP in photos
+pub struct ExifTagger;
+
+fn ascii_field(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(parts) => {
+            let s = String::from_utf8_lossy(parts.first()?)
+                .trim_matches(char::from(0))
+                .trim()
+                .to_string();
+
+            Some(s).filter(|x| !x.is_empty())
+        }
+        _ => None,
+    }
+}
+
+fn uint_field(exif: &Exif, tag: Tag) -> Option<u32> {
+    exif.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+}
+
+fn rational_field(exif: &Exif, tag: Tag) -> Option<(u32, u32)> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(v) => v.first().filter(|x| x.denom != 0).map(|x| (x.num, x.denom)),
+        _ => None,
+    }
+}
+
+/// Degrees from the degrees, minutes and seconds GPS coordinates are kept as
+fn gps_coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: &str) -> Option<f64> {
+    let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(v) if v.len() == 3 && v.iter().all(|x| x.denom != 0) => {
+            v[0].to_f64() + v[1].to_f64() / 60.0 + v[2].to_f64() / 3600.0
+        }
+        _ => return None,
+    };
+
+    if ascii_field(exif, ref_tag).as_deref() == Some(negative_ref) {
+        Some(-degrees)
+    } else {
+        Some(degrees)
+    }
+}
+
+fn exif_tags(exif: &Exif) -> TagEntries {
+    let mut tags = Vec::new();
+
+    let date = [Tag::DateTimeOriginal, Tag::DateTimeDigitized, Tag::DateTime]
+        .iter()
+        .filter_map(|tag| match &exif.get_field(*tag, In::PRIMARY)?.value {
+            Value::Ascii(parts) => exif::DateTime::from_ascii(parts.first()?).ok(),
+            _ => None,
+        })
+        .next();
+
+    if let Some(date) = date {
+        tags.push(autotagger::date(
+            date.year as i64,
+            date.month as u32,
+            date.day as u32,
+            date.hour as u32,
+            date.minute as u32,
+            date.second as u32,
+        ));
+    }
+
+    for (name, tag) in [
+        ("camera_manufacturer", Tag::Make),
+        ("camera_model", Tag::Model),
+        ("camera_software", Tag::Software),
+        ("lens", Tag::LensModel),
+    ] {
+        if let Some(text) = ascii_field(exif, tag) {
+            tags.extend(text_tag(name, &text));
+        }
+    }
+
+    if let Some(iso) = uint_field(exif, Tag::PhotographicSensitivity) {
+        tags.push(number("iso", &iso.to_string()));
+    }
+
+    // Written without the usual slashes, which can't be in a directory name, and sorted in tenths so f2.8 and f3.2 differ
+    if let Some((num, denom)) = rational_field(exif, Tag::FNumber) {
+        let tenths = (num as f64 * 10.0 / denom as f64).round() as i64;
+
+        let aperture = if tenths % 10 == 0 {
+            format!("f{}", tenths / 10)
+        } else {
+            format!("f{}.{}", tenths / 10, tenths % 10)
+        };
+
+        tags.push(("aperture".to_string(), Some((aperture, Some(tenths)))));
+    }
+
+    // In seconds, sorted in microseconds
+    if let Some((num, denom)) = rational_field(exif, Tag::ExposureTime) {
+        let micros = (num as f64 * 1e6 / denom as f64).round() as i64;
+
+        let seconds = format!("{:.6}", micros as f64 / 1e6);
+        let seconds = seconds.trim_end_matches('0').trim_end_matches('.');
+
+        tags.push((
+            "exposure".to_string(),
+            Some((seconds.to_string(), Some(micros))),
+        ));
+    }
+
+    if let Some(orientation) = uint_field(exif, Tag::Orientation) {
+        tags.push(number("orientation", &orientation.to_string()));
+    }
+
+    let latitude = gps_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S");
+    let longitude = gps_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W");
+
+    if let (Some(latitude), Some(longitude)) = (latitude, longitude) {
+        tags.push(value("gps", &format!("{:.6},{:.6}", latitude, longitude)));
+        tags.push(value("latitude", &format!("{:.6}", latitude)));
+        tags.push(value("longitude", &format!("{:.6}", longitude)));
+    }
+
+    // Not a standard EXIF tag, but it's where Windows and most cameras put it
+    if let Some(rating) = uint_field(exif, Tag(exif::Context::Tiff, 0x4746)) {
+        tags.push(number("rating", &rating.to_string()));
+    }
+
+    tags
+}
+
+fn find_xmp(path: &std::path::Path) -> io::Result<Option<String>> {
+    let mut data = Vec::new();
+    fs::File::open(path)?
+        .take(XMP_SEARCH_LIMIT)
+        .read_to_end(&mut data)?;
+
+    let find = |needle: &[u8], from: usize| {
+        data[from..]
+            .windows(needle.len())
+            .position(|x| x == needle)
+            .map(|x| x + from)
+    };
+
+    let Some(start) = find(b"<x:xmpmeta", 0) else {
+        return Ok(None);
+    };
+
+    let closing = b"</x:xmpmeta>";
+    let Some(end) = find(closing, start) else {
+        return Ok(None);
+    };
+
+    Ok(Some(
+        String::from_utf8_lossy(&data[start..end + closing.len()]).to_string(),
+    ))
+}
+
+fn xmp_tags(xmp: &str) -> Result<TagEntries, String> {
+    let doc = roxmltree::Document::parse(xmp).map_err(|err| err.to_string())?;
+
+    let mut tags = Vec::new();
+
+    for subject in doc
+        .descendants()
+        .filter(|x| x.has_tag_name((DC_NS, "subject")))
+    {
+        for keyword in subject.descendants().filter(|x| x.has_tag_name("li")) {
+            tags.extend(keyword.text().and_then(|x| text_tag("keyword", x)));
+        }
+    }
+
+    // Written either as an attribute of the description or as its own element
+    let rating = doc.descendants().find_map(|x| {
+        x.attribute((XMP_NS, "Rating")).or_else(|| {
+            Some(x)
+                .filter(|x| x.has_tag_name((XMP_NS, "Rating")))
+                .and_then(|x| x.text())
+        })
+    });
+
+    if let Some(rating) = rating.and_then(|x| x.trim().parse::<i64>().ok()) {
+        tags.push(number("rating", &rating.to_string()));
+    }
+
+    Ok(tags)
+}
+
+impl Autotagger for ExifTagger {
+    fn name(&self) -> &str {
+        "exif"
+    }
+
+    fn applies_to(&self, source: &TagSource) -> bool {
+        source.magic.starts_with("JPEG image data")
+            || source.magic.starts_with("PNG image data")
+            || source.magic.starts_with("TIFF image data")
+            || source.magic.starts_with("ISO Media, HEIF")
+            || source
+                .extension()
+                .map(|x| RAW_EXTENSIONS.contains(&x.to_lowercase().as_str()))
+                .unwrap_or(false)
+    }
+
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String> {
+        let mut tags = Vec::new();
+
+        let exif = fs::File::open(source.path)
+            .map_err(exif::Error::from)
+            .and_then(|file| {
+                exif::Reader::new().read_from_container(&mut io::BufReader::new(file))
+            });
+
+        match exif {
+            Ok(exif) => tags.append(&mut exif_tags(&exif)),
+            Err(exif::Error::NotFound(_)) => {}
+            Err(err) => return Err(format!("error reading EXIF: {}", err)),
+        }
+
+        let mut xmp_tags = match find_xmp(source.path).map_err(|x| x.to_string())? {
+            Some(xmp) => xmp_tags(&xmp).map_err(|err| format!("error reading XMP: {}", err))?,
+            None => vec![],
+        };
+
+        // XMP is what editors write ratings back to, so it's newer than the EXIF one
+        if xmp_tags.iter().any(|(name, _)| name == "rating") {
+            tags.retain(|(name, _)| name != "rating");
+        }
+
+        tags.append(&mut xmp_tags);
+
+        Ok(tags)
+    }
+}
@@ -0,0 +1,288 @@
+use super::{
+    format_tag, get_points_by_parts, get_tags_for_point, os_str_from_db, path_from_db,
+    tag_entry_to_path_part, tag_fits_path_part, Point, SqliteConnection, Tag, TagEntry, QUERY_RE,
+};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportMode {
+    Symlink,
+    Hardlink,
+    Reflink,
+    Copy,
+}
+
+impl ExportMode {
+    pub fn from_name(name: &str) -> Option<ExportMode> {
+        match name {
+            "symlink" => Some(ExportMode::Symlink),
+            "hardlink" => Some(ExportMode::Hardlink),
+            "reflink" => Some(ExportMode::Reflink),
+            "copy" => Some(ExportMode::Copy),
+            _ => None,
+        }
+    }
+}
+
+fn tag_to_entry(tag: &Tag) -> TagEntry {
+    (
+        tag.name.clone(),
+        tag.value.clone().map(|x| (x, tag.sort_value)),
+    )
+}
+
+fn export_file(mode: ExportMode, source: &Path, target: &Path) -> io::Result<()> {
+    match mode {
+        ExportMode::Symlink => symlink(source, target),
+        ExportMode::Hardlink => fs::hard_link(source, target),
+        ExportMode::Reflink => reflink_copy::reflink(source, target),
+        ExportMode::Copy => fs::copy(source, target).map(|_| ()),
+    }
+}
+
+pub fn export_path(mode: ExportMode, source: &Path, target: &Path) -> io::Result<()> {
+    // Directories can be symlinked as a whole, but the other modes only work on files
+    if mode == ExportMode::Symlink || !source.is_dir() {
+        return export_file(mode, source, target);
+    }
+
+    for entry in walkdir::WalkDir::new(source) {
+        let entry = entry?;
+        let entry_target = target.join(entry.path().strip_prefix(source).unwrap());
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&entry_target)?;
+        } else {
+            export_file(mode, entry.path(), &entry_target)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A query part that only matches a single tag, as `name` or `name = value`, which is what `@flat-info` can hold
+///
+/// Comparisons, `or` and numeric values match by sort value, so could stand for any number of tags
+fn plain_query_tag(query_name: &str) -> Option<String> {
+    if query_name.starts_with('@') || query_name.contains(" or ") {
+        return None;
+    }
+
+    let Some(caps) = QUERY_RE.captures(query_name) else {
+        return Some(query_name.to_string()).filter(|x| !x.contains(['=', '<', '>']));
+    };
+
+    let (tag_name, op, tag_value) = (&caps[1], &caps[2], &caps[3]);
+
+    if op == "="
+        && caps.get(0).map(|x| x.start()) == Some(0)
+        && !tag_value.contains('=')
+        && tag_value.trim() == tag_value
+        && tag_value.parse::<i64>().is_err()
+    {
+        Some(format!("{} = {}", tag_name, tag_value))
+    } else {
+        None
+    }
+}
+
+/// Where a point goes in the exported tree, one tag per level like in `@flatten`, leaving out the tags `@flat-info` gives it
+fn point_export_path(
+    connection: &SqliteConnection,
+    flat_tags: &[String],
+    point: &Point,
+) -> PathBuf {
+    let mut tags = get_tags_for_point(connection, point)
+        .into_iter()
+        .filter(|x| !flat_tags.contains(&format_tag(x)))
+        .collect::<Vec<Tag>>();
+
+    tags.sort_by_key(format_tag);
+
+    let mut path = PathBuf::new();
+
+    for tag in tags {
+        let tag_entry = tag_to_entry(&tag);
+        let tag_name = tag_entry_to_path_part(&tag_entry);
+
+        // It has to read back as the same tag when the export is imported
+        if !tag_fits_path_part(&tag_entry) {
+            warn!(
+                "Tag {:?} on {:?} can't be a directory name, leaving it out",
+                tag_name, point.name
+            );
+            continue;
+        }
+
+        path.push(os_str_from_db(&tag_name));
+    }
+
+    path
+}
+
+/// Writes out the points matching a query in the layout stores are read in, so the result can be imported again
+pub fn export_query(
+    connection: &SqliteConnection,
+    query: &str,
+    export_dir: &Path,
+    mode: ExportMode,
+) {
+    let query_names = query
+        .split('/')
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<&str>>();
+
+    fs::create_dir_all(export_dir).expect("Error creating export dir");
+
+    // Tags from the query are implied by where the export came from, like with @flatten, anything fancier stays in the paths
+    let flat_tags = query_names
+        .iter()
+        .filter_map(|x| plain_query_tag(x))
+        .collect::<Vec<String>>();
+
+    if !flat_tags.is_empty() {
+        fs::write(export_dir.join("@flat-info"), flat_tags.join("/"))
+            .expect("Error writing @flat-info");
+    }
+
+    let mut used_paths = HashSet::new();
+
+    for point in get_points_by_parts(connection, &query_names) {
+        let Some(source) = &point.path else {
+            warn!(
+                "{:?} (id {:?}) has no path, skipping it",
+                point.name, point.id
+            );
+            continue;
+        };
+
+        let tag_dir = export_dir.join(point_export_path(connection, &flat_tags, &point));
+
+        let mut target = tag_dir.join(os_str_from_db(&point.name));
+
+        // Points with the same name and tags would land on top of each other, so tell them apart by id
+        if !used_paths.insert(target.clone()) {
+            target = tag_dir.join(os_str_from_db(&format!("{}.{}", point.name, point.id)));
+            used_paths.insert(target.clone());
+        }
+
+        if point.dir {
+            target.push("@dir");
+        }
+
+        if fs::symlink_metadata(&target).is_ok() {
+            println!("{:?} already exists, skipping it", target);
+            continue;
+        }
+
+        fs::create_dir_all(target.parent().unwrap()).expect("Error creating export dir");
+
+        match export_path(mode, &path_from_db(source), &target) {
+            Ok(()) => println!("{:?} -> {:?}", source, target),
+            Err(err) => error!("Error exporting {:?} to {:?}: {}", source, target, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::store_path_to_name_and_tags;
+    use crate::utils::JoinSource;
+    use crate::{embedded_migrations, last_insert_id, tag_point, NewPoint};
+    use diesel::prelude::*;
+
+    fn add_point(connection: &SqliteConnection, name: &str, tags: &[(&str, Option<&str>)]) {
+        use crate::schema::points;
+
+        diesel::insert_into(points::table)
+            .values(&NewPoint {
+                name: name.to_string(),
+                path: Some(format!("/nowhere/{}", name)),
+                hash: name.to_string(),
+                dir: false,
+                hash_algorithm: "blake3".to_string(),
+            })
+            .execute(connection)
+            .unwrap();
+
+        let id = last_insert_id(connection);
+
+        for (tag_name, tag_value) in tags {
+            let tag_content = tag_value.map(|x| (x.to_string(), x.parse::<i64>().ok()));
+            tag_point(
+                connection,
+                id,
+                tag_name.to_string(),
+                tag_content,
+                &JoinSource::Manual,
+            );
+        }
+    }
+
+    #[test]
+    fn exports_import_as_the_same_tags() {
+        let connection = SqliteConnection::establish(":memory:").unwrap();
+        embedded_migrations::run(&connection).unwrap();
+
+        add_point(
+            &connection,
+            "relativity.pdf",
+            &[
+                ("shared", None),
+                ("title", Some("E = mc²")),
+                ("x = y", None),
+                ("@flatten", None),
+                ("@dir", None),
+                ("..", None),
+                ("year", Some("1905")),
+                ("kept", None),
+            ],
+        );
+        add_point(
+            &connection,
+            "plain.txt",
+            &[("shared", None), ("a", Some("b"))],
+        );
+
+        let export_dir = std::env::temp_dir().join(format!("ffs-export-{}", std::process::id()));
+        export_query(&connection, "shared", &export_dir, ExportMode::Symlink);
+
+        let mut imported = walkdir::WalkDir::new(&export_dir)
+            .into_iter()
+            .map(|x| x.unwrap())
+            .filter(|x| x.path_is_symlink())
+            .map(|x| {
+                let (name, tags) =
+                    store_path_to_name_and_tags(x.path().strip_prefix(&export_dir).unwrap())
+                        .unwrap();
+                let mut parts = tags
+                    .iter()
+                    .map(tag_entry_to_path_part)
+                    .collect::<Vec<String>>();
+                parts.sort();
+                (name, parts)
+            })
+            .collect::<Vec<(String, Vec<String>)>>();
+        imported.sort();
+
+        let flat_info = fs::read_to_string(export_dir.join("@flat-info")).unwrap();
+        fs::remove_dir_all(&export_dir).unwrap();
+
+        assert_eq!(flat_info, "shared");
+        assert_eq!(
+            imported,
+            vec![
+                ("plain.txt".to_string(), vec!["a = b".to_string()]),
+                (
+                    "relativity.pdf".to_string(),
+                    vec!["kept".to_string(), "year = 1905".to_string()]
+                ),
+            ]
+        );
+    }
+}
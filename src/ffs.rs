@@ -1,12 +1,20 @@
-use super::{get_points_by_parts, get_tags_for_point, get_tags_for_points, schema, Point, Tag};
+use super::{
+    canonical_store_path, get_points_by_parts, get_tags_for_point, get_tags_for_points,
+    import_store_path, os_str_from_db, os_str_to_db, path_parts_to_tags, schema, Point, StoreDir,
+    Tag, INFINITE_QUERY_RE,
+};
+use crate::autotagger::Autotaggers;
 use diesel::prelude::*;
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyOpen, Request,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow,
 };
-use libc::{ENOENT, ENOTDIR};
+use libc::{EBADF, EEXIST, EINVAL, EIO, ENOENT, ENOSYS, ENOTDIR, EROFS};
 use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
 use std::path::Component;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -32,7 +40,10 @@ impl<'a> Iterator for PathNames<'a> {
     fn next(&mut self) -> Option<&'a str> {
         loop {
             match self.inner.next() {
-                Some(Component::Normal(p)) => break Some(p.to_str().unwrap()),
+                // Names coming in through FUSE are turned into their database form first, see `Ffs::child_path`
+                Some(Component::Normal(p)) => {
+                    break Some(p.to_str().expect("ffs paths are always in database form"))
+                }
                 Some(_) => continue,
                 None => break None,
             }
@@ -76,6 +87,13 @@ pub struct Ffs {
     extra_dirs: Vec<PathBuf>,
 
     dir_entries: HashMap<u64, Vec<(u64, FileType, String)>>,
+
+    store_dir: Option<StoreDir>,
+    autotaggers: Autotaggers,
+
+    // Files dropped into the mount are written straight to where they belong in the store
+    ino_to_ingest: HashMap<u64, PathBuf>,
+    fh_to_ingest: HashMap<u64, (fs::File, PathBuf)>,
 }
 
 const TTL: Duration = Duration::from_secs(1);
@@ -168,7 +186,7 @@ fn parse_path(path: &Path) -> ParsedPath {
     }
 }
 
-fn format_tag(tag: &Tag) -> String {
+pub fn format_tag(tag: &Tag) -> String {
     match &tag.value {
         Some(v) => format!("{} = {}", tag.name, v),
         None => tag.name.to_string(),
@@ -176,7 +194,11 @@ fn format_tag(tag: &Tag) -> String {
 }
 
 impl Ffs {
-    pub fn new(connection: SqliteConnection) -> Ffs {
+    pub fn new(
+        connection: SqliteConnection,
+        store_dir: Option<StoreDir>,
+        autotaggers: Autotaggers,
+    ) -> Ffs {
         Ffs {
             db: connection,
 
@@ -196,16 +218,26 @@ impl Ffs {
             extra_dirs: Vec::new(),
 
             dir_entries: HashMap::new(),
+
+            store_dir,
+            autotaggers,
+
+            ino_to_ingest: HashMap::new(),
+            fh_to_ingest: HashMap::new(),
         }
     }
 
+    fn ingest_attr(&self, ino: u64) -> Option<FileAttr> {
+        let md = fs::metadata(self.ino_to_ingest.get(&ino)?).ok()?;
+
+        Some(basic_file(ino, md.size(), md.blocks()))
+    }
+
     pub fn lookup_point_by_name(&mut self, path: &Path) -> Option<Point> {
         if let Some(last_part) = path.file_name() {
             use schema::points;
 
-            if let Some(Ok(possible_id)) = last_part
-                .to_str()
-                .unwrap()
+            if let Some(Ok(possible_id)) = os_str_to_db(last_part)
                 .split('.')
                 .last()
                 .map(|x| x.parse::<i32>())
@@ -251,12 +283,30 @@ impl Ffs {
         self.ino_to_path.get(&ino).map(|x| x.as_path())
     }
 
+    /// The path of a name in a dir, with the name in the form the database has names in, which is always valid UTF-8
+    fn child_path(&self, parent_ino: u64, name: &OsStr) -> PathBuf {
+        let name = os_str_to_db(name);
+
+        match self.read_ino(parent_ino) {
+            None => PathBuf::from(name),
+            Some(x) => Path::new(x).join(name),
+        }
+    }
+
     fn internal_lookup(
         &mut self,
         path: &Path,
         maybe_parent_ino: Option<u64>,
     ) -> Result<FileAttr, ()> {
-        let name = path.file_name().unwrap_or(OsStr::new("")).to_str().unwrap();
+        let name = path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+
+        if let Some(attr) = self
+            .path_to_ino
+            .get(path)
+            .and_then(|ino| self.ingest_attr(*ino))
+        {
+            return Ok(attr);
+        }
 
         match parse_path(&path) {
             ParsedPath::Flattened(filter_path_names, flat_path_names, query_names) => {
@@ -414,11 +464,7 @@ impl Filesystem for Ffs {
     }
 
     fn lookup(&mut self, _req: &Request, parent_ino: u64, name_os_str: &OsStr, reply: ReplyEntry) {
-        let maybe_parent_path = self.read_ino(parent_ino);
-        let path = match maybe_parent_path {
-            None => PathBuf::from(name_os_str),
-            Some(x) => Path::new(x).join(name_os_str),
-        };
+        let path = self.child_path(parent_ino, name_os_str);
 
         let file_attr = match self.internal_lookup(&path, Some(parent_ino)) {
             Ok(x) => x,
@@ -441,7 +487,7 @@ impl Filesystem for Ffs {
     ) {
         if let Some(entries) = self.dir_entries.get(&ino) {
             for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
-                reply.add(entry.0, (i + 1) as i64, entry.1, entry.2.clone());
+                reply.add(entry.0, (i + 1) as i64, entry.1, os_str_from_db(&entry.2));
             }
 
             // Cache should only be used once (for staggered reads), delete once it's done reading
@@ -498,7 +544,7 @@ impl Filesystem for Ffs {
                                 let full_tag_name = format_tag(&tag);
                                 if !query_path
                                     .iter()
-                                    .any(|x| x.to_str().unwrap() == full_tag_name.as_str())
+                                    .any(|x| x.to_str() == Some(full_tag_name.as_str()))
                                 {
                                     full_tags.push(full_tag_name);
                                 }
@@ -596,7 +642,7 @@ impl Filesystem for Ffs {
             }
 
             for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
-                reply.add(entry.0, (i + 1) as i64, entry.1, entry.2.clone());
+                reply.add(entry.0, (i + 1) as i64, entry.1, os_str_from_db(&entry.2));
             }
 
             if offset == 0 {
@@ -609,7 +655,7 @@ impl Filesystem for Ffs {
 
     fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
         match self.ino_to_point.get(&ino) {
-            Some(Point { path: Some(p), .. }) => reply.data(p.as_bytes()),
+            Some(Point { path: Some(p), .. }) => reply.data(os_str_from_db(p).as_bytes()),
             _ => reply.error(ENOENT),
         }
     }
@@ -623,15 +669,167 @@ impl Filesystem for Ffs {
         _umask: u32,
         reply: ReplyEntry,
     ) {
-        let path = match self.read_ino(parent) {
-            None => PathBuf::from(name_os_str),
-            Some(x) => Path::new(x).join(name_os_str),
-        };
+        let path = self.child_path(parent, name_os_str);
 
         reply.entry(&TTL, &basic_directory(self.new_ino(&path)), 0);
         self.extra_dirs.push(path);
     }
 
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name_os_str: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let Some(store_dir) = self.store_dir.clone() else {
+            reply.error(EROFS);
+            return;
+        };
+
+        let path = self.child_path(parent, name_os_str);
+        let name = os_str_to_db(name_os_str);
+
+        // The directories it's dropped into are the tags it gets
+        let mut tag_parts = match parse_path(&path) {
+            ParsedPath::Flattened(_, _, query_names) => query_names,
+            ParsedPath::Normal(path_names) => path_names,
+        };
+        tag_parts.pop();
+
+        // Only plain tags say what something should be tagged with, queries don't
+        if tag_parts
+            .iter()
+            .any(|x| x.starts_with('@') || x.contains(" or ") || INFINITE_QUERY_RE.is_match(x))
+        {
+            reply.error(EINVAL);
+            return;
+        }
+
+        let Ok(tags) = path_parts_to_tags(&tag_parts) else {
+            reply.error(EINVAL);
+            return;
+        };
+
+        let target = canonical_store_path(store_dir.path(), &name, &tags, false);
+
+        if fs::symlink_metadata(&target).is_ok() {
+            reply.error(EEXIST);
+            return;
+        }
+
+        let file = match fs::create_dir_all(target.parent().unwrap())
+            .and_then(|_| fs::File::create(&target))
+        {
+            Ok(file) => file,
+            Err(err) => {
+                error!("Error creating {:?}: {}", target, err);
+                reply.error(err.raw_os_error().unwrap_or(EIO));
+                return;
+            }
+        };
+
+        let ino = self.new_ino(&path);
+        let fh = self.new_fh(&path);
+
+        self.ino_to_ingest.insert(ino, target.clone());
+        self.fh_to_ingest.insert(fh, (file, target));
+
+        reply.created(&TTL, &basic_file(ino, 0, 0), 0, fh, flags as u32);
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let Some((file, _)) = self.fh_to_ingest.get(&fh) else {
+            reply.error(EBADF);
+            return;
+        };
+
+        match file.write_at(data, offset as u64) {
+            Ok(written) => reply.written(written as u32),
+            Err(err) => reply.error(err.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<std::time::SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let Some(target) = self.ino_to_ingest.get(&ino) else {
+            reply.error(ENOSYS);
+            return;
+        };
+
+        if let Some(size) = size {
+            if let Err(err) = fs::OpenOptions::new()
+                .write(true)
+                .open(target)
+                .and_then(|x| x.set_len(size))
+            {
+                reply.error(err.raw_os_error().unwrap_or(EIO));
+                return;
+            }
+        }
+
+        match self.ingest_attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.fh_to_path.remove(&fh);
+
+        // Once it's fully written, it can be imported like anything else in the store
+        if let (Some((file, target)), Some(store_dir)) =
+            (self.fh_to_ingest.remove(&fh), &self.store_dir)
+        {
+            drop(file);
+
+            self.ino_to_ingest.remove(&ino);
+
+            import_store_path(&self.db, store_dir, &target, vec![], &self.autotaggers);
+        }
+
+        reply.ok();
+    }
+
     fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let Some(path) = self.read_ino(ino) else {
             reply.error(ENOENT);
@@ -0,0 +1,219 @@
+use super::{schema, Join, Point, SqliteConnection, Tag};
+use crate::store::*;
+use crate::utils::*;
+use diesel::prelude::*;
+use rayon::prelude::*;
+
+/// Everything `check` found wrong with the database
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Points with a path that doesn't exist anymore
+    pub missing: Vec<Point>,
+    /// Points whose contents don't match their hash, along with the hash they have now
+    pub mismatched: Vec<(Point, String)>,
+    /// Joins to a point or tag that doesn't exist
+    pub dangling_joins: Vec<Join>,
+    /// Tags that aren't on any point, which untagging leaves behind all the time so they aren't a problem
+    pub orphan_tags: Vec<Tag>,
+    /// Points that were hashed with another algorithm and so weren't checked
+    pub outdated: i64,
+    pub hashed: usize,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty() && self.dangling_joins.is_empty()
+    }
+}
+
+/// Looks through the database for problems, hashing every point with a path or only `sample` random ones of them
+pub fn check(connection: &SqliteConnection, sample: Option<i64>) -> FsckReport {
+    use schema::{joins, points, tags};
+
+    let algorithm = db_hash_algorithm(connection);
+
+    let missing = points::dsl::points
+        .filter(points::dsl::path.is_not_null())
+        .load::<Point>(connection)
+        .expect("Error loading points")
+        .into_iter()
+        .filter(|x| {
+            path_from_db(x.path.as_ref().unwrap())
+                .symlink_metadata()
+                .is_err()
+        })
+        .collect::<Vec<Point>>();
+
+    let outdated = points::dsl::points
+        .filter(points::dsl::path.is_not_null())
+        .filter(points::dsl::hash_algorithm.ne(algorithm.name()))
+        .count()
+        .get_result::<i64>(connection)
+        .expect("Error counting points");
+
+    let to_hash = points::dsl::points
+        .filter(points::dsl::path.is_not_null())
+        .filter(points::dsl::hash_algorithm.eq(algorithm.name()));
+
+    let to_hash = match sample {
+        Some(sample) => to_hash
+            .order(diesel::dsl::sql::<diesel::sql_types::Integer>("RANDOM()"))
+            .limit(sample)
+            .load::<Point>(connection),
+        None => to_hash.load::<Point>(connection),
+    }
+    .expect("Error loading points");
+
+    let hashed = to_hash.len();
+
+    let mismatched = to_hash
+        .into_par_iter()
+        .filter_map(|point| {
+            let hash = hash_point(&point, algorithm)?;
+
+            if hash == point.hash {
+                None
+            } else {
+                Some((point, hash))
+            }
+        })
+        .collect::<Vec<(Point, String)>>();
+
+    let dangling_joins = joins::dsl::joins
+        .filter(
+            joins::dsl::point_id
+                .ne_all(points::dsl::points.select(points::dsl::id))
+                .or(joins::dsl::tag_id.ne_all(tags::dsl::tags.select(tags::dsl::id))),
+        )
+        .load::<Join>(connection)
+        .expect("Error loading joins");
+
+    // Joins to missing points don't count, those go away with the dangling joins
+    let used_tag_ids = joins::dsl::joins
+        .filter(joins::dsl::point_id.eq_any(points::dsl::points.select(points::dsl::id)))
+        .select(joins::dsl::tag_id);
+
+    let orphan_tags = tags::dsl::tags
+        .filter(tags::dsl::id.ne_all(used_tag_ids))
+        .load::<Tag>(connection)
+        .expect("Error loading tags");
+
+    FsckReport {
+        missing,
+        mismatched,
+        dangling_joins,
+        orphan_tags,
+        outdated,
+        hashed,
+    }
+}
+
+/// Fixes what it can of a report, returning how many problems were left as they are
+///
+/// Changed contents get their new hash recorded, unless another point already has it
+pub fn repair(connection: &SqliteConnection, report: &FsckReport) -> usize {
+    use schema::{joins, points, tags};
+
+    let algorithm = db_hash_algorithm(connection);
+    let mut unrepaired = 0;
+
+    connection
+        .transaction::<_, diesel::result::Error, _>(|| {
+            // Chunked to stay under SQLite's limit on bound parameters
+            for dangling_joins in report.dangling_joins.chunks(500) {
+                diesel::delete(
+                    joins::dsl::joins
+                        .filter(joins::dsl::id.eq_any(dangling_joins.iter().map(|x| x.id))),
+                )
+                .execute(connection)?;
+            }
+
+            for orphan_tags in report.orphan_tags.chunks(500) {
+                diesel::delete(
+                    tags::dsl::tags.filter(tags::dsl::id.eq_any(orphan_tags.iter().map(|x| x.id))),
+                )
+                .execute(connection)?;
+            }
+
+            for point in &report.missing {
+                forget_store_path(connection, &path_from_db(point.path.as_ref().unwrap()));
+            }
+
+            for (point, hash) in &report.mismatched {
+                let updated = diesel::update(points::dsl::points.find(point.id))
+                    .set((
+                        points::dsl::hash.eq(hash),
+                        points::dsl::hash_algorithm.eq(algorithm.name()),
+                    ))
+                    .execute(connection);
+
+                match updated {
+                    Ok(_) => {
+                        // Have the next scan import it again, its tags might have changed with it
+                        uncache_store_path(connection, &path_from_db(point.path.as_ref().unwrap()));
+                    }
+                    Err(err) => {
+                        println!(
+                            "Couldn't update the hash of {:?} (id {}): {}",
+                            point.name, point.id, err
+                        );
+                        unrepaired += 1;
+                    }
+                }
+            }
+
+            Ok(())
+        })
+        .expect("Error repairing database");
+
+    unrepaired
+}
+
+pub fn print_report(report: &FsckReport) {
+    for point in &report.missing {
+        println!(
+            "{:?} (id {}) is missing from {:?}",
+            point.name,
+            point.id,
+            path_from_db(point.path.as_ref().unwrap())
+        );
+    }
+
+    for (point, _) in &report.mismatched {
+        println!(
+            "{:?} (id {}) doesn't match its hash at {:?}",
+            point.name,
+            point.id,
+            path_from_db(point.path.as_ref().unwrap())
+        );
+    }
+
+    for join in &report.dangling_joins {
+        println!(
+            "join {} links point {} and tag {}, one of which doesn't exist",
+            join.id, join.point_id, join.tag_id
+        );
+    }
+
+    if !report.orphan_tags.is_empty() {
+        println!(
+            "{} tags aren't on any point anymore, --repair clears them out",
+            report.orphan_tags.len()
+        );
+    }
+
+    if report.outdated > 0 {
+        println!(
+            "{} points weren't checked since they have hashes from another algorithm, run `ffs rehash` first",
+            report.outdated
+        );
+    }
+
+    println!(
+        "Hashed {} points: {} missing, {} changed, {} dangling joins",
+        report.hashed,
+        report.missing.len(),
+        report.mismatched.len(),
+        report.dangling_joins.len()
+    );
+}
@@ -0,0 +1,1008 @@
+#![feature(proc_macro_hygiene)]
+#![feature(let_else)]
+
+use self::diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use lazy_static::lazy_static;
+use rayon::prelude::*;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+#[macro_use]
+extern crate serde_derive;
+
+extern crate magic;
+
+lazy_static! {
+    pub static ref QUERY_RE: Regex = Regex::new(r"(\w+)\s*(<=|>=|<|>|=|!=)\s*(.+)").unwrap();
+    pub static ref INFINITE_QUERY_RE: Regex = Regex::new(r"(\w+)\s*(<=|>=|<|>|!=)\s*(.+)").unwrap();
+}
+
+mod archive_tagger;
+mod audio_tagger;
+mod autotagger;
+mod command_tagger;
+mod document_tagger;
+mod dry_run;
+mod exif_tagger;
+mod export;
+mod ffs;
+mod fsck;
+mod models;
+pub mod schema;
+mod store;
+mod store_rules;
+mod tag_rules;
+mod utils;
+mod video_tagger;
+mod watch;
+
+use autotagger::{TaggerResults, ERROR_TAG};
+use ffs::*;
+pub use models::*;
+use store::*;
+use utils::*;
+
+// What a library build needs to write and register its own taggers
+pub use autotagger::{flag, number, text_tag, value, Autotagger, Autotaggers, TagSource};
+pub use utils::{TagContent, TagEntries, TagEntry};
+
+embed_migrations!();
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FfsConfig {
+    magic_file: String,
+    db_url: String,
+    store_dir: Option<StoreDir>,
+    delegate_dirs: Vec<StoreDir>,
+    #[serde(default = "default_auto_migrate")]
+    auto_migrate: bool,
+    #[serde(default = "default_import_batch_size")]
+    import_batch_size: usize,
+    #[serde(default = "default_watch")]
+    watch: bool,
+    #[serde(default)]
+    manage_store: bool,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    /// Turns autotaggers on or off by name
+    #[serde(default)]
+    autotaggers: HashMap<String, bool>,
+    #[serde(default)]
+    tag_rules: Vec<tag_rules::TagRuleConfig>,
+    #[serde(default)]
+    tag_commands: Vec<command_tagger::TagCommandConfig>,
+}
+
+fn default_auto_migrate() -> bool {
+    true
+}
+
+fn default_import_batch_size() -> usize {
+    1000
+}
+
+fn default_watch() -> bool {
+    true
+}
+
+fn configure_connection(connection: &SqliteConnection) {
+    // Needed for joins to be cleaned up along with their points and tags
+    connection
+        .execute("PRAGMA foreign_keys = ON")
+        .expect("Error enabling foreign keys");
+
+    // Lets the mount keep reading while imports write, and makes committing batches cheaper
+    connection
+        .execute("PRAGMA journal_mode = WAL")
+        .expect("Error enabling WAL mode");
+    connection
+        .execute("PRAGMA synchronous = NORMAL")
+        .expect("Error setting synchronous mode");
+
+    // The mount and the store watcher each have their own connection, so wait on each other rather than failing
+    connection
+        .execute("PRAGMA busy_timeout = 5000")
+        .expect("Error setting busy timeout");
+}
+
+fn tag_point(
+    connection: &SqliteConnection,
+    id: i32,
+    tag_name: String,
+    tag_content: Option<(String, Option<i64>)>,
+    source: &JoinSource,
+) {
+    use schema::{joins, tags};
+
+    let (tag_value, tag_sort_value) = match tag_content {
+        Some((tag_value, tag_sort_value)) => (Some(tag_value), tag_sort_value),
+        None => (None, None),
+    };
+
+    // The unique indexes on tags and joins turn these into no-ops when the row already exists
+    diesel::insert_or_ignore_into(tags::table)
+        .values(&NewTag {
+            name: tag_name.clone(),
+            value: tag_value.clone(),
+            sort_value: tag_sort_value,
+        })
+        .execute(connection)
+        .expect("Error saving new tag");
+
+    let tag_id = (match tag_value {
+        Some(ref tag_value) => tags::dsl::tags
+            .select(tags::dsl::id)
+            .filter(tags::dsl::name.eq(&tag_name))
+            .filter(tags::dsl::value.eq(tag_value))
+            .first::<i32>(connection),
+        None => tags::dsl::tags
+            .select(tags::dsl::id)
+            .filter(tags::dsl::name.eq(&tag_name))
+            .filter(tags::dsl::value.is_null())
+            .first::<i32>(connection),
+    })
+    .expect("error searching tags");
+
+    let existing_source = joins::dsl::joins
+        .filter(joins::dsl::point_id.eq(id))
+        .filter(joins::dsl::tag_id.eq(tag_id))
+        .select(joins::dsl::source)
+        .first::<String>(connection)
+        .optional()
+        .expect("error searching joins");
+
+    match existing_source {
+        None => {
+            diesel::insert_into(joins::table)
+                .values(&NewJoin {
+                    point_id: id,
+                    tag_id,
+                    source: source.to_db(),
+                })
+                .execute(connection)
+                .expect("Error saving new join");
+        }
+        // A tag added by hand stays when the autotagger that also gave it stops doing so
+        Some(existing_source) if source.outranks(&JoinSource::from_db(&existing_source)) => {
+            diesel::update(
+                joins::dsl::joins
+                    .filter(joins::dsl::point_id.eq(id))
+                    .filter(joins::dsl::tag_id.eq(tag_id)),
+            )
+            .set(joins::dsl::source.eq(source.to_db()))
+            .execute(connection)
+            .expect("Error updating join");
+        }
+        Some(_) => {}
+    }
+}
+
+/// Removes the tags a source gave a point that aren't in what it gives it now, only ones called `only_name` if that's set
+fn retract_stale_tags(
+    connection: &SqliteConnection,
+    id: i32,
+    source: &JoinSource,
+    tags: &TagEntries,
+    only_name: Option<&str>,
+) {
+    use schema::{joins, tags};
+
+    let joins = joins::dsl::joins
+        .filter(joins::dsl::point_id.eq(id))
+        .filter(joins::dsl::source.eq(source.to_db()))
+        .load::<Join>(connection)
+        .expect("error searching joins");
+
+    let joined_tags = tags::dsl::tags
+        .filter(tags::dsl::id.eq_any(joins.iter().map(|x| x.tag_id)))
+        .load::<Tag>(connection)
+        .expect("error searching tags");
+
+    let stale_tag_ids = joined_tags.iter().filter(|tag| {
+        only_name.map(|x| tag.name == x).unwrap_or(true)
+            && !tags.iter().any(|(tag_name, tag_content)| {
+                tag.name == *tag_name && tag.value.as_ref() == tag_content.as_ref().map(|x| &x.0)
+            })
+    });
+
+    diesel::delete(
+        joins::dsl::joins
+            .filter(joins::dsl::point_id.eq(id))
+            .filter(joins::dsl::tag_id.eq_any(stale_tag_ids.map(|x| x.id))),
+    )
+    .execute(connection)
+    .expect("Error deleting join");
+}
+
+fn untag_point(connection: &SqliteConnection, id: i32, tag_name: &str, tag_content: &TagContent) {
+    use schema::{joins, tags};
+
+    let tag_ids = match tag_content {
+        Some((tag_value, _)) => tags::dsl::tags
+            .select(tags::dsl::id)
+            .filter(tags::dsl::name.eq(tag_name))
+            .filter(tags::dsl::value.eq(tag_value))
+            .load::<i32>(connection),
+        None => tags::dsl::tags
+            .select(tags::dsl::id)
+            .filter(tags::dsl::name.eq(tag_name))
+            .filter(tags::dsl::value.is_null())
+            .load::<i32>(connection),
+    }
+    .expect("error searching tags");
+
+    diesel::delete(
+        joins::dsl::joins
+            .filter(joins::dsl::point_id.eq(id))
+            .filter(joins::dsl::tag_id.eq_any(tag_ids)),
+    )
+    .execute(connection)
+    .expect("Error deleting join");
+}
+
+fn update_point_by_path<'a>(
+    connection: &'a SqliteConnection,
+    name: String,
+    path_str: &str,
+    (hash, dir): (String, bool),
+    tagged: TaggerResults,
+    tags: TagEntries,
+    source: &JoinSource,
+) {
+    use schema::points;
+
+    let existing_points_by_path = points::dsl::points
+        .filter(points::dsl::path.eq(path_str))
+        .limit(1)
+        .load::<Point>(connection)
+        .expect("error searching points");
+
+    let existing_points = points::dsl::points
+        .filter(points::dsl::hash.eq(&hash))
+        .limit(1)
+        .load::<Point>(connection)
+        .expect("error searching points");
+
+    let (maybe_point, point_id) = match existing_points_by_path.get(0) {
+        Some(x) => (Some(x), x.id),
+        None => match existing_points.get(0) {
+            Some(x) => (Some(x), x.id),
+            None => {
+                diesel::insert_into(points::table)
+                    .values(&NewPoint {
+                        name,
+                        path: Some(path_str.to_string()),
+                        hash: hash.to_string(),
+                        dir,
+                        hash_algorithm: db_hash_algorithm(connection).name().to_string(),
+                    })
+                    .execute(connection)
+                    .expect("Error saving new point");
+
+                (None, last_insert_id(connection))
+            }
+        },
+    };
+
+    for (tag_name, tag_content) in tags {
+        tag_point(connection, point_id, tag_name, tag_content, source)
+    }
+
+    let point = match maybe_point {
+        Some(x) => x.clone(),
+        None => points::dsl::points
+            .find(point_id)
+            .first::<Point>(connection)
+            .unwrap(),
+    };
+
+    update_point(
+        connection,
+        |_| tagged,
+        Some(path_str),
+        Some(&hash),
+        &point,
+        false,
+    );
+}
+
+/// `autotag` is only asked for the tags of the point's path when it still has one
+///
+/// With `claim_unknown`, joins from before sources were kept count as an autotagger's when it gives tags of the same name
+fn update_point(
+    connection: &SqliteConnection,
+    autotag: impl FnOnce(&str) -> TaggerResults,
+    new_path: Option<&str>,
+    new_hash: Option<&str>,
+    point: &Point,
+    claim_unknown: bool,
+) {
+    use schema::points;
+
+    let path = match (&point.path, new_path) {
+        (None, Some(new_path)) => {
+            diesel::update(points::dsl::points.find(point.id))
+                .set(points::dsl::path.eq(new_path))
+                .execute(connection)
+                .expect("Error updating point");
+
+            Some(new_path)
+        }
+        (Some(current_path), Some(new_path)) if current_path != new_path => {
+            diesel::update(points::dsl::points.find(point.id))
+                .set(points::dsl::path.eq(new_path))
+                .execute(connection)
+                .expect("Error updating point");
+
+            Some(new_path)
+        }
+        (Some(current_path), None) if fs::metadata(path_from_db(current_path)).is_err() => {
+            diesel::update(points::dsl::points.find(point.id))
+                .set(points::dsl::path.eq(new_path))
+                .execute(connection)
+                .expect("Error updating point");
+
+            None
+        }
+        (Some(current_path), _) => Some(&current_path[..]),
+        (None, _) => None,
+    };
+
+    if let Some(path) = path {
+        let tagged = autotag(path);
+
+        let tagged = tagged
+            .into_iter()
+            .map(|(tagger, tags)| {
+                let failed = tags.is_err();
+
+                // The error itself is only logged, it could be anything and tags end up as file names
+                let tags = tags.unwrap_or_else(|_| {
+                    vec![(ERROR_TAG.to_string(), Some((tagger.clone(), None)))]
+                });
+
+                (JoinSource::Autotagger(tagger), tags, failed)
+            })
+            .collect::<Vec<(JoinSource, TagEntries, bool)>>();
+
+        // Everything stale goes first, so a tag one autotagger drops and another still gives is kept
+        for (source, tags, failed) in &tagged {
+            // A tagger that failed keeps what it found before, the file might only be unreadable for now
+            let only_name = if *failed { Some(ERROR_TAG) } else { None };
+
+            retract_stale_tags(connection, point.id, source, tags, only_name);
+
+            if claim_unknown && !*failed {
+                let tag_names = tags.iter().map(|x| &x.0).collect::<HashSet<&String>>();
+
+                for tag_name in tag_names {
+                    retract_stale_tags(
+                        connection,
+                        point.id,
+                        &JoinSource::Unknown,
+                        tags,
+                        Some(tag_name),
+                    );
+                }
+            }
+        }
+
+        for (source, tags, _) in tagged {
+            for (tag_name, tag_content) in tags {
+                tag_point(connection, point.id, tag_name, tag_content, &source)
+            }
+        }
+    }
+
+    if let Some(new_hash) = new_hash {
+        if point.hash != new_hash {
+            diesel::update(points::dsl::points.find(point.id))
+                .set((
+                    points::dsl::hash.eq(new_hash),
+                    points::dsl::hash_algorithm.eq(db_hash_algorithm(connection).name()),
+                ))
+                .execute(connection)
+                .expect("Error updating point");
+        }
+    }
+}
+
+fn load_stores(
+    connection: &SqliteConnection,
+    cfg: &FfsConfig,
+    autotaggers: &Autotaggers,
+    full: bool,
+) {
+    let algorithm = db_hash_algorithm(connection);
+    if algorithm != cfg.hash_algorithm {
+        warn!(
+            "Database is hashed with {} but the config asks for {}, run `ffs rehash` to switch",
+            algorithm.name(),
+            cfg.hash_algorithm.name()
+        );
+    } else {
+        use schema::points;
+
+        let outdated = points::dsl::points
+            .filter(points::dsl::hash_algorithm.ne(algorithm.name()))
+            .filter(points::dsl::path.is_not_null())
+            .count()
+            .get_result::<i64>(connection)
+            .expect("Error counting points");
+
+        if outdated > 0 {
+            warn!(
+                "{} points have hashes from an older version, run `ffs rehash` to update them",
+                outdated
+            );
+        }
+    }
+
+    let mut malformed = Vec::new();
+
+    for store_dir in cfg.store_dir.iter().chain(cfg.delegate_dirs.iter()) {
+        malformed.append(&mut load_store(
+            connection,
+            store_dir,
+            autotaggers,
+            cfg.import_batch_size,
+            full,
+        ));
+    }
+
+    print_malformed(&malformed);
+}
+
+/// Switches the database to the configured hash algorithm and rehashes every point made with another one
+fn rehash_points(connection: &SqliteConnection, cfg: &FfsConfig) {
+    use schema::points;
+
+    let algorithm = cfg.hash_algorithm;
+    set_db_hash_algorithm(connection, algorithm);
+
+    let points = points::dsl::points
+        .filter(points::dsl::hash_algorithm.ne(algorithm.name()))
+        .filter(points::dsl::path.is_not_null())
+        .load::<Point>(connection)
+        .expect("Error loading points");
+
+    rehash(connection, algorithm, &points, cfg.import_batch_size);
+}
+
+/// Rehashes dir points a migration marked as hashed the old way, as migrations can't hash anything themselves
+fn rehash_migrated_dirs(connection: &SqliteConnection, cfg: &FfsConfig) {
+    use schema::points;
+
+    let algorithm = db_hash_algorithm(connection);
+
+    let points = points::dsl::points
+        .filter(points::dsl::dir)
+        .filter(points::dsl::hash_algorithm.ne(algorithm.name()))
+        .filter(points::dsl::path.is_not_null())
+        .load::<Point>(connection)
+        .expect("Error loading points");
+
+    if !points.is_empty() {
+        println!("Rehashing {} dirs hashed by an older version", points.len());
+        rehash(connection, algorithm, &points, cfg.import_batch_size);
+    }
+}
+
+/// Each batch is committed along with the algorithms of its points, so an interrupted rehash carries on where it stopped
+fn rehash(
+    connection: &SqliteConnection,
+    algorithm: HashAlgorithm,
+    points: &[Point],
+    batch_size: usize,
+) {
+    use schema::points;
+
+    let mut missing = Vec::new();
+
+    for batch in points.chunks(batch_size) {
+        let hashes = batch
+            .par_iter()
+            .map(|point| hash_point(point, algorithm))
+            .collect::<Vec<Option<String>>>();
+
+        connection
+            .transaction::<_, diesel::result::Error, _>(|| {
+                for (point, hash) in batch.iter().zip(hashes) {
+                    let Some(hash) = hash else {
+                        missing.push(point);
+                        continue;
+                    };
+
+                    println!("{:?}: {}", point.name, hash);
+
+                    diesel::update(points::dsl::points.find(point.id))
+                        .set((
+                            points::dsl::hash.eq(hash),
+                            points::dsl::hash_algorithm.eq(algorithm.name()),
+                        ))
+                        .execute(connection)?;
+                }
+
+                Ok(())
+            })
+            .expect("Error rehashing points");
+    }
+
+    if !missing.is_empty() {
+        println!(
+            "{} points are missing and keep their old hashes:",
+            missing.len()
+        );
+
+        for point in missing {
+            println!("  {:?} (id {}) at {:?}", point.name, point.id, point.path);
+        }
+    }
+}
+
+/// Moves a point in a managed store after its tags changed, with `change` applied to the tags its current path gives it
+fn reorganize_managed_point(
+    connection: &SqliteConnection,
+    cfg: &FfsConfig,
+    autotaggers: &Autotaggers,
+    id: i32,
+    change: impl FnOnce(&mut TagEntries),
+) {
+    use schema::points;
+
+    let (true, Some(store_dir)) = (cfg.manage_store, &cfg.store_dir) else {
+        return;
+    };
+
+    let point = points::dsl::points
+        .find(id)
+        .first::<Point>(connection)
+        .expect("Error loading point");
+
+    let Some(mut tags) = store_path_tags(store_dir.path(), &point) else {
+        return;
+    };
+
+    change(&mut tags);
+
+    reorganize_store_point(connection, store_dir, &point, &tags, autotaggers);
+}
+
+/// Runs the ffs command line, `customize` gets to register extra taggers alongside the builtin ones
+pub fn run(customize: fn(&mut Autotaggers)) {
+    let settings = config::Config::builder()
+        .add_source(config::File::with_name("config").required(false))
+        .add_source(config::Environment::with_prefix("FFS"))
+        .build()
+        .expect("Error in config");
+
+    let cfg = settings
+        .try_deserialize::<FfsConfig>()
+        .expect("Config not valid");
+
+    if cfg.import_batch_size == 0 {
+        panic!("import_batch_size can't be 0");
+    }
+
+    let mut autotaggers = Autotaggers::from_config(&cfg);
+    customize(&mut autotaggers);
+
+    let mut args = env::args();
+
+    let command = args.nth(1).unwrap_or_else(|| "".to_string());
+
+    let connection = SqliteConnection::establish(&cfg.db_url).expect("Error connecting to db");
+
+    // This has to happen before foreign keys are enabled, as migrations rebuild tables other tables reference
+    if cfg.auto_migrate || command == "migrate" {
+        embedded_migrations::run_with_output(&connection, &mut io::stdout())
+            .expect("Error running migrations");
+    }
+
+    configure_connection(&connection);
+
+    if cfg.auto_migrate || command == "migrate" {
+        rehash_migrated_dirs(&connection, &cfg);
+    }
+
+    match command.as_str() {
+        "mount" => {
+            let mountpoint = match env::args_os().nth(2) {
+                Some(mountpoint) => mountpoint,
+                None => {
+                    println!("where do i mount bitch");
+                    return;
+                }
+            };
+
+            load_stores(&connection, &cfg, &autotaggers, false);
+
+            if cfg.watch {
+                watch::spawn_store_watcher(cfg.clone(), customize);
+            }
+
+            let ffs = Ffs::new(connection, cfg.store_dir.clone(), autotaggers);
+
+            fuser::mount2(
+                ffs,
+                mountpoint,
+                &[
+                    // fuser::MountOption::AllowRoot,
+                    // fuser::MountOption::RO,
+                    // fuser::MountOption::AutoUnmount,
+                ],
+            )
+            .unwrap();
+        }
+        "add" => {
+            let mut path_arg = args.next();
+
+            let ingest = match path_arg.as_deref() {
+                Some("--ingest") => Some(false),
+                Some("--ingest-copy") => Some(true),
+                _ => None,
+            };
+
+            if ingest.is_some() {
+                path_arg = args.next();
+            }
+
+            let path_str = match path_arg {
+                Some(path) => path,
+                None => {
+                    println!("what file are u adding bitch");
+                    return;
+                }
+            };
+
+            let path = Path::new(&path_str);
+
+            let full_path = fs::canonicalize(path).expect("error reading path");
+            let full_path_str = path_to_db(&full_path);
+
+            let tags = args.map(|x| parse_tag_arg(&x)).collect::<TagEntries>();
+
+            let name = os_str_to_db(path.file_name().expect("bad file path provided"));
+
+            if let Some(copy) = ingest {
+                let Some(store_dir) = &cfg.store_dir else {
+                    println!("there's no store_dir to ingest into bitch");
+                    return;
+                };
+
+                connection
+                    .transaction::<_, diesel::result::Error, _>(|| {
+                        ingest_path(
+                            &connection,
+                            store_dir,
+                            &full_path,
+                            &tags,
+                            copy,
+                            &autotaggers,
+                        );
+                        Ok(())
+                    })
+                    .expect("Error ingesting point");
+
+                return;
+            }
+
+            let hash = match hash_path(&full_path, db_hash_algorithm(&connection)) {
+                Ok(hash) => hash,
+                Err(err) => {
+                    println!("couldn't read {:?}: {}", full_path, err);
+                    return;
+                }
+            };
+
+            // Worked out up front, so the database isn't locked while the taggers run
+            let tagged = autotaggers.tags(&full_path);
+
+            connection
+                .transaction::<_, diesel::result::Error, _>(|| {
+                    update_point_by_path(
+                        &connection,
+                        name,
+                        &full_path_str,
+                        hash,
+                        tagged,
+                        tags,
+                        &JoinSource::Manual,
+                    );
+                    Ok(())
+                })
+                .expect("Error adding point");
+        }
+        // retag also clears out stale tags from before sources were kept, which update-all leaves alone
+        "update-all" | "retag" => {
+            use schema::points;
+
+            let points = points::dsl::points
+                .load::<Point>(&connection)
+                .expect("Error loading points");
+
+            for batch in points.chunks(cfg.import_batch_size) {
+                connection
+                    .transaction::<_, diesel::result::Error, _>(|| {
+                        for point in batch {
+                            update_point(
+                                &connection,
+                                |path| autotaggers.tags(&path_from_db(path)),
+                                None,
+                                None,
+                                point,
+                                command == "retag",
+                            );
+                        }
+                        Ok(())
+                    })
+                    .expect("Error updating points");
+            }
+        }
+        "remove" => {
+            use schema::points;
+
+            let id_str = match args.next() {
+                Some(path) => path,
+                None => {
+                    println!("what point are u removing bitch");
+                    return;
+                }
+            };
+
+            let id = match id_str.parse::<i32>() {
+                Ok(id) => id,
+                Err(_) => {
+                    println!("{:?} is not a valid ID", id_str);
+                    return;
+                }
+            };
+
+            diesel::delete(points::dsl::points.find(id))
+                .execute(&connection)
+                .expect("Error deleting point");
+
+            println!("Deleted {:?}", id);
+        }
+        "tag" => {
+            let id_str = match args.next() {
+                Some(path) => path,
+                None => {
+                    println!("what point are u tagging bitch");
+                    return;
+                }
+            };
+
+            let id = match id_str.parse::<i32>() {
+                Ok(id) => id,
+                Err(_) => {
+                    println!("{:?} is not a valid ID", id_str);
+                    return;
+                }
+            };
+
+            let tag_name = match args.next() {
+                Some(path) => path,
+                None => {
+                    println!("what is the tag bitch");
+                    return;
+                }
+            };
+
+            let tag_content = args.next().map(|x| (x.to_string(), x.parse::<i64>().ok()));
+
+            tag_point(
+                &connection,
+                id,
+                tag_name.clone(),
+                tag_content.clone(),
+                &JoinSource::Manual,
+            );
+
+            reorganize_managed_point(&connection, &cfg, &autotaggers, id, |tags| {
+                tags.push((tag_name, tag_content))
+            });
+        }
+        "tags" => {
+            use schema::{joins, tags};
+
+            let id_str = match args.next() {
+                Some(path) => path,
+                None => {
+                    println!("what point are u looking at bitch");
+                    return;
+                }
+            };
+
+            let id = match id_str.parse::<i32>() {
+                Ok(id) => id,
+                Err(_) => {
+                    println!("{:?} is not a valid ID", id_str);
+                    return;
+                }
+            };
+
+            let joins = joins::dsl::joins
+                .filter(joins::dsl::point_id.eq(id))
+                .load::<Join>(&connection)
+                .expect("Error loading joins");
+
+            for join in joins {
+                let tag = tags::dsl::tags
+                    .find(join.tag_id)
+                    .first::<Tag>(&connection)
+                    .expect("Error loading tag");
+
+                match tag.value {
+                    Some(value) => println!("{}={} ({})", tag.name, value, join.source),
+                    None => println!("{} ({})", tag.name, join.source),
+                }
+            }
+        }
+        "untag" => {
+            use schema::joins;
+
+            let id_str = match args.next() {
+                Some(path) => path,
+                None => {
+                    println!("what point are u tagging bitch");
+                    return;
+                }
+            };
+
+            let id = match id_str.parse::<i32>() {
+                Ok(id) => id,
+                Err(_) => {
+                    println!("{:?} is not a valid ID", id_str);
+                    return;
+                }
+            };
+
+            let tag_name = match args.next() {
+                Some(path) => path,
+                None => {
+                    println!("what is the tag query bitch");
+                    return;
+                }
+            };
+
+            let p = get_tags_by_parts(&connection, &[&tag_name]);
+
+            let tag = match &p[0][..] {
+                [x] => x,
+                _ => {
+                    println!("tag {:?} not found", tag_name);
+                    return;
+                }
+            };
+
+            diesel::delete(
+                joins::dsl::joins
+                    .filter(joins::dsl::point_id.eq(id))
+                    .filter(joins::dsl::tag_id.eq(tag.id)),
+            )
+            .execute(&connection)
+            .expect("Error deleting point");
+
+            println!("Removed tag {:?} (id {:?}) from {:?}", tag_name, tag.id, id);
+
+            reorganize_managed_point(&connection, &cfg, &autotaggers, id, |tags| {
+                tags.retain(|(name, content)| {
+                    name != &tag.name || content.as_ref().map(|x| &x.0) != tag.value.as_ref()
+                })
+            });
+        }
+        "scan" => {
+            let flags = args.collect::<Vec<String>>();
+            let full = flags.iter().any(|x| x == "--full");
+
+            if flags.iter().any(|x| x == "--dry-run") {
+                let store_dirs = cfg
+                    .store_dir
+                    .iter()
+                    .chain(cfg.delegate_dirs.iter())
+                    .collect::<Vec<&StoreDir>>();
+
+                let plan = dry_run::plan_stores(&connection, &store_dirs, full);
+
+                if flags.iter().any(|x| x == "--json") {
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&dry_run::plan_to_json(&plan)).unwrap()
+                    );
+                } else {
+                    dry_run::print_plan(&plan);
+                }
+
+                return;
+            }
+
+            load_stores(&connection, &cfg, &autotaggers, full);
+        }
+        "rehash" => {
+            rehash_points(&connection, &cfg);
+        }
+        "export" => {
+            let query = match args.next() {
+                Some(query) => query,
+                None => {
+                    println!("what are u exporting bitch");
+                    return;
+                }
+            };
+
+            let export_dir = match args.next() {
+                Some(export_dir) => export_dir,
+                None => {
+                    println!("where are u exporting to bitch");
+                    return;
+                }
+            };
+
+            let mode_name = args.next().unwrap_or_else(|| "symlink".to_string());
+
+            let mode = match export::ExportMode::from_name(&mode_name) {
+                Some(mode) => mode,
+                None => {
+                    println!(
+                        "{:?} is not a valid mode, use symlink, hardlink, reflink or copy",
+                        mode_name
+                    );
+                    return;
+                }
+            };
+
+            export::export_query(&connection, &query, Path::new(&export_dir), mode);
+        }
+        "fsck" => {
+            let flags = args.collect::<Vec<String>>();
+
+            let sample = match flags.iter().position(|x| x == "--sample") {
+                Some(i) => match flags.get(i + 1).and_then(|x| x.parse::<i64>().ok()) {
+                    Some(sample) => Some(sample),
+                    None => {
+                        println!("how many points are u sampling bitch");
+                        return;
+                    }
+                },
+                None => None,
+            };
+
+            let report = fsck::check(&connection, sample);
+            fsck::print_report(&report);
+
+            // Unused tags get cleared out even when there's nothing else to fix
+            let repaired =
+                flags.iter().any(|x| x == "--repair") && fsck::repair(&connection, &report) == 0;
+
+            // Like e2fsck, 1 means everything found was fixed and 4 that some of it is still there
+            if report.is_clean() {
+                return;
+            }
+
+            if repaired {
+                println!("Repaired");
+                std::process::exit(1);
+            }
+
+            std::process::exit(4);
+        }
+        "migrate" => {
+            println!("Database is up to date");
+        }
+        _ => {
+            println!("CNF");
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use super::schema::{joins, points, tags};
+use super::schema::{joins, points, scan_cache, tags};
 
 #[derive(Identifiable, Queryable, Associations, Debug, Clone)]
 pub struct Point {
@@ -7,16 +7,17 @@ pub struct Point {
     pub path: Option<String>,
     pub hash: String,
     pub dir: bool,
+    pub hash_algorithm: String,
 }
 
 #[derive(Insertable, Debug)]
 #[table_name = "points"]
 pub struct NewPoint {
-    pub id: i32,
     pub name: String,
     pub path: Option<String>,
     pub hash: String,
     pub dir: bool,
+    pub hash_algorithm: String,
 }
 
 #[derive(Identifiable, Queryable, Associations, Debug, Clone)]
@@ -30,7 +31,6 @@ pub struct Tag {
 #[derive(Insertable, Debug)]
 #[table_name = "tags"]
 pub struct NewTag {
-    pub id: i32,
     pub name: String,
     pub value: Option<String>,
     pub sort_value: Option<i64>,
@@ -43,12 +43,23 @@ pub struct Join {
     pub id: i32,
     pub tag_id: i32,
     pub point_id: i32,
+    pub source: String,
 }
 
 #[derive(Insertable, Debug)]
 #[table_name = "joins"]
 pub struct NewJoin {
-    pub id: i32,
     pub tag_id: i32,
     pub point_id: i32,
+    pub source: String,
+}
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[table_name = "scan_cache"]
+pub struct ScanCacheEntry {
+    pub path: String,
+    pub store_dir: String,
+    pub size: i64,
+    pub mtime: i64,
+    pub inode: i64,
 }
@@ -3,6 +3,7 @@ table! {
         id -> Integer,
         tag_id -> Integer,
         point_id -> Integer,
+        source -> Text,
     }
 }
 
@@ -13,6 +14,31 @@ table! {
         path -> Nullable<Text>,
         hash -> Text,
         dir -> Bool,
+        hash_algorithm -> Text,
+    }
+}
+
+table! {
+    scan_cache (path) {
+        path -> Text,
+        store_dir -> Text,
+        size -> BigInt,
+        mtime -> BigInt,
+        inode -> BigInt,
+    }
+}
+
+table! {
+    scan_progress (store_dir) {
+        store_dir -> Text,
+        last_path -> Text,
+    }
+}
+
+table! {
+    settings (name) {
+        name -> Text,
+        value -> Text,
     }
 }
 
@@ -25,8 +51,4 @@ table! {
     }
 }
 
-allow_tables_to_appear_in_same_query!(
-    joins,
-    points,
-    tags,
-);
+allow_tables_to_appear_in_same_query!(joins, points, scan_cache, scan_progress, settings, tags,);
@@ -0,0 +1,1064 @@
+use super::{schema, untag_point, update_point_by_path, Point, ScanCacheEntry, SqliteConnection};
+use crate::autotagger::{Autotaggers, TaggerResults};
+use crate::export::{export_path, ExportMode};
+use crate::store_rules::*;
+use crate::utils::*;
+use diesel::prelude::*;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+/// What scanning a store does with the symlinks it comes across
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Leave them out of the store
+    #[default]
+    Skip,
+    /// Treat them as whatever they point to, at the path of the link
+    Follow,
+    /// Like `Follow`, but points are recorded at the path the link resolves to
+    Target,
+    /// Import the links themselves, hashed by where they point
+    Link,
+}
+
+impl SymlinkPolicy {
+    pub fn follows(self) -> bool {
+        matches!(self, SymlinkPolicy::Follow | SymlinkPolicy::Target)
+    }
+}
+
+/// A store or delegate dir in the config, either just its path or a table with options for it
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum StoreDir {
+    Path(String),
+    Options {
+        path: String,
+        #[serde(default)]
+        symlinks: SymlinkPolicy,
+    },
+}
+
+impl StoreDir {
+    pub fn path(&self) -> &str {
+        match self {
+            StoreDir::Path(path) | StoreDir::Options { path, .. } => path,
+        }
+    }
+
+    pub fn symlinks(&self) -> SymlinkPolicy {
+        match self {
+            StoreDir::Path(_) => SymlinkPolicy::default(),
+            StoreDir::Options { symlinks, .. } => *symlinks,
+        }
+    }
+}
+
+pub fn path_parts_to_tags(path_parts: &[&str]) -> Result<TagEntries, String> {
+    let mut tags: TagEntries = Vec::new();
+
+    for path_part in path_parts {
+        let tag: TagEntry = match path_part
+            .split('=')
+            .map(|x| x.trim())
+            .collect::<Vec<&str>>()[..]
+        {
+            [tag_name, tag_value, tag_sort_value] => (
+                tag_name.to_string(),
+                Some((
+                    tag_value.to_string(),
+                    Some(tag_sort_value.parse::<i64>().map_err(|_| {
+                        format!("bad sort value {:?} in {:?}", tag_sort_value, path_part)
+                    })?),
+                )),
+            ),
+            [tag_name, tag_value] => (tag_name.to_string(), Some((tag_value.to_string(), None))),
+            [tag_name] => (tag_name.to_string(), None),
+            _ => return Err(format!("too many '=' in {:?}", path_part)),
+        };
+
+        tags.push(tag);
+    }
+
+    Ok(tags)
+}
+
+pub fn store_path_to_name_and_tags(path: &Path) -> Result<(String, TagEntries), String> {
+    let name = os_str_to_db(path.file_name().ok_or("path has no file name")?);
+
+    let tag_parts = path
+        .parent()
+        .unwrap_or(Path::new(""))
+        .iter()
+        .map(os_str_to_db)
+        .collect::<Vec<String>>();
+
+    let tags = path_parts_to_tags(
+        tag_parts
+            .iter()
+            .map(|x| x.as_str())
+            .collect::<Vec<&str>>()
+            .as_slice(),
+    )?;
+
+    Ok((name, tags))
+}
+
+/// Works out the name and tags of the point at a path in a store, if that path is one
+pub fn store_entry(
+    store_dir: &str,
+    path: &Path,
+    is_dir: bool,
+) -> Result<Option<(String, TagEntries)>, String> {
+    let rel_path = path
+        .strip_prefix(store_dir)
+        .expect("Path in store dir should be in store dir");
+
+    if rel_path
+        .parent()
+        .is_some_and(|x| x.iter().any(|x| x == "@dir"))
+    {
+        return Ok(None);
+    }
+
+    if let Some(file_name) = rel_path
+        .file_name()
+        .filter(|x| RULE_FILES.contains(&x.to_str().unwrap_or("")))
+    {
+        info!("Not importing {:?} meta-file", file_name);
+        return Ok(None);
+    }
+
+    if is_dir {
+        if rel_path.file_name() != Some("@dir".as_ref()) {
+            return Ok(None);
+        }
+
+        let rel_parent_path = rel_path.parent().expect("Store entry dir path is invalid");
+        store_path_to_name_and_tags(rel_parent_path).map(Some)
+    } else {
+        store_path_to_name_and_tags(rel_path).map(Some)
+    }
+}
+
+/// The path of the point a path in a store belongs to, which is the enclosing `@dir` for anything inside one
+pub fn store_point_path(store_dir: &str, path: &Path) -> PathBuf {
+    let rel_path = path
+        .strip_prefix(store_dir)
+        .expect("Path in store dir should be in store dir");
+
+    match rel_path.iter().position(|x| x == "@dir") {
+        Some(pos) => Path::new(store_dir).join(rel_path.iter().take(pos + 1).collect::<PathBuf>()),
+        None => path.to_path_buf(),
+    }
+}
+
+fn cache_store_path(
+    connection: &SqliteConnection,
+    store_dir: &str,
+    path_str: &str,
+    fingerprint: Fingerprint,
+) -> QueryResult<usize> {
+    use schema::scan_cache;
+
+    let (size, mtime, inode) = fingerprint;
+
+    diesel::replace_into(scan_cache::table)
+        .values(&ScanCacheEntry {
+            path: path_str.to_string(),
+            store_dir: store_dir.to_string(),
+            size,
+            mtime,
+            inode,
+        })
+        .execute(connection)
+}
+
+pub fn uncache_store_path(connection: &SqliteConnection, path: &Path) {
+    use schema::scan_cache;
+
+    let path_str = path_to_db(path);
+
+    diesel::delete(scan_cache::dsl::scan_cache.find(&path_str))
+        .execute(connection)
+        .expect("Error clearing scan cache");
+
+    for cached_path in scan_cache::dsl::scan_cache
+        .select(scan_cache::dsl::path)
+        .filter(scan_cache::dsl::path.like(format!("{}/%", path_str)))
+        .load::<String>(connection)
+        .expect("Error loading scan cache")
+    {
+        if path_from_db(&cached_path).starts_with(path) {
+            diesel::delete(scan_cache::dsl::scan_cache.find(&cached_path))
+                .execute(connection)
+                .expect("Error clearing scan cache");
+        }
+    }
+}
+
+/// Whether a store path is reached through a symlinked dir somewhere below the store dir, which itself may well sit behind one
+fn through_symlink(store_dir: &str, path: &Path) -> bool {
+    path.ancestors()
+        .take_while(|x| *x != Path::new(store_dir))
+        .any(|x| {
+            fs::symlink_metadata(x)
+                .map(|md| md.file_type().is_symlink())
+                .unwrap_or(false)
+        })
+}
+
+/// Links resolving back into the store are recorded under the store dir as configured, not wherever it really is
+fn rebase_on_store_dir(store_dir: &str, target: PathBuf) -> PathBuf {
+    let rel_path = fs::canonicalize(store_dir)
+        .ok()
+        .and_then(|x| target.strip_prefix(x).ok().map(|x| x.to_path_buf()));
+
+    match rel_path {
+        Some(rel_path) => Path::new(store_dir).join(rel_path),
+        None => target,
+    }
+}
+
+/// The path a store entry's point is recorded at and whether it's a symlink imported as one, or None if its store leaves it out
+fn resolve_store_entry(
+    store_dir: &StoreDir,
+    path: &Path,
+    is_symlink: bool,
+) -> Option<(PathBuf, bool)> {
+    match store_dir.symlinks() {
+        SymlinkPolicy::Skip if is_symlink => {
+            warn!(
+                "Store path {:?} is a symlink, set symlinks for {:?} to import it",
+                path,
+                store_dir.path()
+            );
+            None
+        }
+        SymlinkPolicy::Link if is_symlink => Some((path.to_path_buf(), true)),
+        // Anything under a followed symlinked dir resolves elsewhere too, not just the link itself
+        SymlinkPolicy::Target if is_symlink || through_symlink(store_dir.path(), path) => {
+            match fs::canonicalize(path) {
+                Ok(target) => Some((rebase_on_store_dir(store_dir.path(), target), false)),
+                Err(err) => {
+                    error!("Error resolving store path {:?}: {}", path, err);
+                    None
+                }
+            }
+        }
+        _ => Some((path.to_path_buf(), false)),
+    }
+}
+
+fn store_entry_fingerprint(point_path: &Path, link: bool) -> io::Result<Fingerprint> {
+    if link {
+        fingerprint_link(point_path)
+    } else {
+        fingerprint_path(point_path)
+    }
+}
+
+pub fn store_entry_hash(
+    point_path: &Path,
+    link: bool,
+    algorithm: HashAlgorithm,
+) -> io::Result<(String, bool)> {
+    if link {
+        hash_link(point_path, algorithm)
+    } else {
+        hash_path(point_path, algorithm)
+    }
+}
+
+/// Imports a single point in a store, as when it has been created or changed
+pub fn import_store_path(
+    connection: &SqliteConnection,
+    store_dir: &StoreDir,
+    path: &Path,
+    mut extra_tags: TagEntries,
+    autotaggers: &Autotaggers,
+) {
+    let Ok(md) = fs::symlink_metadata(path) else {
+        // Already gone again, the removal will be handled on its own
+        return;
+    };
+
+    let Some((point_path, link)) =
+        resolve_store_entry(store_dir, path, md.file_type().is_symlink())
+    else {
+        return;
+    };
+
+    let is_dir = if link {
+        false
+    } else {
+        match fs::metadata(&point_path) {
+            Ok(md) => md.is_dir(),
+            Err(err) => {
+                error!("Error reading store path {:?}: {}", path, err);
+                return;
+            }
+        }
+    };
+
+    let mut rules = StoreRules::new(store_dir.path());
+
+    if rules.ignores_path(path, is_dir) {
+        debug!("Ignoring store path {:?}", path);
+        return;
+    }
+
+    let (name, mut new_tags, mut tags) = match (
+        store_entry(store_dir.path(), path, is_dir),
+        rules.tags(path),
+    ) {
+        (Ok(None), _) => return,
+        (Ok(Some((name, new_tags))), Ok(tags)) => (name, new_tags, tags),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("Not importing store path {:?}: {}", path, err);
+            return;
+        }
+    };
+
+    tags.append(&mut new_tags);
+    tags.append(&mut extra_tags);
+
+    let point_path_str = path_to_db(&point_path);
+
+    // It can be gone or unreadable again by now, which the next change or scan will pick up
+    let (hash, fingerprint) = match (
+        store_entry_hash(&point_path, link, db_hash_algorithm(connection)),
+        store_entry_fingerprint(&point_path, link),
+    ) {
+        (Ok(hash), Ok(fingerprint)) => (hash, fingerprint),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("Error reading store path {:?}: {}", path, err);
+            return;
+        }
+    };
+
+    // Like the hash, worked out before the database is locked for the writes
+    let tagged = autotaggers.tags(&point_path);
+
+    println!("{:?}: {:?} -> {:?}", name, tags, point_path);
+
+    store_transaction(connection, || {
+        update_point_by_path(
+            connection,
+            name,
+            &point_path_str,
+            hash,
+            tagged,
+            tags,
+            &JoinSource::Store,
+        );
+
+        cache_store_path(connection, store_dir.path(), &path_to_db(path), fingerprint)
+            .expect("Error updating scan cache");
+    });
+}
+
+/// Follows a point that was moved within or between stores, swapping the tags its old path gave it for those of its new one
+pub fn relocate_store_path(
+    connection: &SqliteConnection,
+    (old_store_dir, old_path): (&StoreDir, &Path),
+    (new_store_dir, new_path): (&StoreDir, &Path),
+    autotaggers: &Autotaggers,
+) {
+    use schema::points;
+
+    // The new path may have been picked up already, when its directory was created in the same burst of changes
+    let Some(point) = points::dsl::points
+        .filter(
+            points::dsl::path
+                .eq(path_to_db(old_path))
+                .or(points::dsl::path.eq(path_to_db(new_path))),
+        )
+        .first::<Point>(connection)
+        .optional()
+        .expect("error searching points")
+    else {
+        import_store_path(connection, new_store_dir, new_path, vec![], autotaggers);
+        return;
+    };
+
+    // A malformed old path never gave the point any tags to take away
+    let mut old_tags = store_entry(old_store_dir.path(), old_path, point.dir)
+        .ok()
+        .flatten()
+        .map(|(_, tags)| tags)
+        .unwrap_or_default();
+    old_tags.append(
+        &mut StoreRules::new(old_store_dir.path())
+            .tags(old_path)
+            .unwrap_or_default(),
+    );
+
+    let mut new_rules = StoreRules::new(new_store_dir.path());
+
+    if new_rules.ignores_path(new_path, point.dir) {
+        forget_store_path(connection, old_path);
+        return;
+    }
+
+    let (new_name, mut new_tags, mut new_flat_tags) = match (
+        store_entry(new_store_dir.path(), new_path, point.dir),
+        new_rules.tags(new_path),
+    ) {
+        (Ok(None), _) => {
+            forget_store_path(connection, old_path);
+            return;
+        }
+        (Ok(Some((name, tags))), Ok(flat_tags)) => (name, tags, flat_tags),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("Not importing store path {:?}: {}", new_path, err);
+            forget_store_path(connection, old_path);
+            return;
+        }
+    };
+
+    new_tags.append(&mut new_flat_tags);
+
+    store_transaction(connection, || {
+        for (tag_name, tag_content) in old_tags {
+            if !new_tags.contains(&(tag_name.clone(), tag_content.clone())) {
+                untag_point(connection, point.id, &tag_name, &tag_content);
+            }
+        }
+
+        diesel::update(points::dsl::points.find(point.id))
+            .set((
+                points::dsl::name.eq(&new_name),
+                points::dsl::path.eq(path_to_db(new_path)),
+            ))
+            .execute(connection)
+            .expect("Error updating point");
+
+        uncache_store_path(connection, old_path);
+    });
+
+    import_store_path(connection, new_store_dir, new_path, vec![], autotaggers);
+}
+
+/// Marks the points at or under a path as missing, as when it has been deleted or moved out of the stores
+pub fn forget_store_path(connection: &SqliteConnection, path: &Path) {
+    use schema::points;
+
+    let path_str = path_to_db(path);
+
+    store_transaction(connection, || {
+        let candidates = points::dsl::points
+            .filter(
+                points::dsl::path
+                    .eq(&path_str)
+                    .or(points::dsl::path.like(format!("{}/%", path_str))),
+            )
+            .load::<Point>(connection)
+            .expect("error searching points");
+
+        for point in candidates {
+            // LIKE is case insensitive and treats _ and % specially, so double check
+            if !path_from_db(point.path.as_ref().unwrap()).starts_with(path) {
+                continue;
+            }
+
+            println!("{:?}: missing from {:?}", point.name, point.path);
+
+            diesel::update(points::dsl::points.find(point.id))
+                .set(points::dsl::path.eq(None::<String>))
+                .execute(connection)
+                .expect("Error updating point");
+        }
+
+        uncache_store_path(connection, path);
+    });
+}
+
+/// Runs a short run of writes in its own transaction, or a savepoint in one that's already open
+///
+/// A panic rolls it back before carrying on up, so the connection isn't left stuck in a transaction
+fn store_transaction(connection: &SqliteConnection, writes: impl FnOnce()) {
+    let mut panicked = None;
+
+    let result = connection.transaction::<_, diesel::result::Error, _>(|| {
+        panic::catch_unwind(AssertUnwindSafe(writes)).map_err(|err| {
+            panicked = Some(err);
+            diesel::result::Error::RollbackTransaction
+        })
+    });
+
+    if let Some(err) = panicked {
+        panic::resume_unwind(err);
+    }
+
+    result.expect("Error committing store change");
+}
+
+/// Builds the store path part for a tag, the inverse of `path_parts_to_tags`
+pub fn tag_entry_to_path_part((tag_name, tag_content): &TagEntry) -> String {
+    match tag_content {
+        Some((tag_value, Some(sort_value)))
+            if tag_value.parse::<i64>().ok() != Some(*sort_value) =>
+        {
+            format!("{} = {} = {}", tag_name, tag_value, sort_value)
+        }
+        Some((tag_value, _)) => format!("{} = {}", tag_name, tag_value),
+        None => tag_name.to_string(),
+    }
+}
+
+/// Whether a tag comes back as itself from its store directory
+///
+/// `/` and `=` would split it differently, spaces around it get trimmed, and `..` or `@dir` mean something else as a path
+pub fn tag_fits_path_part((tag_name, tag_content): &TagEntry) -> bool {
+    let fits = |x: &str| !x.contains(['/', '=']) && x.trim() == x;
+
+    !tag_name.is_empty()
+        && !tag_name.starts_with('@')
+        && tag_name != "."
+        && tag_name != ".."
+        && fits(tag_name)
+        && tag_content.as_ref().is_none_or(|(x, _)| fits(x))
+}
+
+/// Where a point with these tags belongs in a managed store, one directory per tag sorted like in `@flatten`
+pub fn canonical_store_path(store_dir: &str, name: &str, tags: &TagEntries, dir: bool) -> PathBuf {
+    let mut path_parts = tags
+        .iter()
+        .filter(|x| {
+            if tag_fits_path_part(x) {
+                true
+            } else {
+                warn!(
+                    "Tag {:?} can't be a store directory, leaving it out",
+                    tag_entry_to_path_part(x)
+                );
+                false
+            }
+        })
+        .map(tag_entry_to_path_part)
+        .collect::<Vec<String>>();
+
+    path_parts.sort();
+    path_parts.dedup();
+
+    let mut path = Path::new(store_dir).join(
+        path_parts
+            .iter()
+            .map(|x| os_str_from_db(x))
+            .collect::<PathBuf>(),
+    );
+    path.push(os_str_from_db(name));
+
+    if dir {
+        path.push("@dir");
+    }
+
+    path
+}
+
+/// Renames a path, falling back to copying it when it has to cross filesystems
+fn move_path(source: &Path, target: &Path, copy: bool) -> io::Result<()> {
+    if !copy {
+        match fs::rename(source, target) {
+            Err(err) if err.raw_os_error() == Some(libc::EXDEV) => {}
+            x => return x,
+        }
+    }
+
+    export_path(ExportMode::Copy, source, target)?;
+
+    if !copy {
+        if source.is_dir() {
+            fs::remove_dir_all(source)?;
+        } else {
+            fs::remove_file(source)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Cleans up the tag directories a point was moved out of
+fn remove_empty_dirs(store_dir: &str, dir: &Path) {
+    for dir in dir.ancestors() {
+        if !dir.starts_with(store_dir) || dir == Path::new(store_dir) {
+            break;
+        }
+
+        // Fails for directories that still have something in them, which is where we stop
+        if fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// Moves (or copies) a file or directory into the store under the path its tags give it, and imports it from there
+pub fn ingest_path(
+    connection: &SqliteConnection,
+    store_dir: &StoreDir,
+    source: &Path,
+    tags: &TagEntries,
+    copy: bool,
+    autotaggers: &Autotaggers,
+) -> Option<PathBuf> {
+    let name = os_str_to_db(source.file_name()?);
+
+    let target = canonical_store_path(store_dir.path(), &name, tags, source.is_dir());
+
+    if fs::symlink_metadata(&target).is_ok() {
+        println!("{:?} is already in the store", target);
+        return None;
+    }
+
+    fs::create_dir_all(target.parent().unwrap()).expect("Error creating store dir");
+
+    if let Err(err) = move_path(source, &target, copy) {
+        error!("Error moving {:?} into the store: {}", source, err);
+        return None;
+    }
+
+    // Tags that couldn't be made part of the path still need to end up on the point
+    import_store_path(connection, store_dir, &target, tags.clone(), autotaggers);
+
+    Some(target)
+}
+
+/// Moves a point in a managed store to where its tags now say it should be
+pub fn reorganize_store_point(
+    connection: &SqliteConnection,
+    store_dir: &StoreDir,
+    point: &Point,
+    tags: &TagEntries,
+    autotaggers: &Autotaggers,
+) {
+    let Some(path) = point.path.as_ref().map(|x| path_from_db(x)) else {
+        return;
+    };
+
+    if !path.starts_with(store_dir.path()) {
+        return;
+    }
+
+    let target = canonical_store_path(store_dir.path(), &point.name, tags, point.dir);
+
+    if target == path {
+        return;
+    }
+
+    if fs::symlink_metadata(&target).is_ok() {
+        warn!(
+            "Not moving {:?} to {:?}, something is already there",
+            path, target
+        );
+        return;
+    }
+
+    fs::create_dir_all(target.parent().unwrap()).expect("Error creating store dir");
+
+    if let Err(err) = fs::rename(&path, &target) {
+        error!("Error moving {:?} to {:?}: {}", path, target, err);
+        return;
+    }
+
+    println!("{:?} moved to {:?}", path, target);
+
+    relocate_store_path(
+        connection,
+        (store_dir, &path),
+        (store_dir, &target),
+        autotaggers,
+    );
+
+    remove_empty_dirs(store_dir.path(), path.parent().unwrap());
+}
+
+/// The tags a point in a store gets from its path there
+pub fn store_path_tags(store_dir: &str, point: &Point) -> Option<TagEntries> {
+    let path = path_from_db(point.path.as_ref()?);
+
+    if !path.starts_with(store_dir) {
+        return None;
+    }
+
+    store_entry(store_dir, &path, point.dir)
+        .ok()
+        .flatten()
+        .map(|(_, tags)| tags)
+}
+
+/// A store entry that needs importing, found while walking a store
+pub struct PendingEntry {
+    pub name: String,
+    pub path: PathBuf,
+    pub point_path: PathBuf,
+    pub link: bool,
+    pub tags: TagEntries,
+    pub fingerprint: Fingerprint,
+}
+
+impl PendingEntry {
+    pub fn hash(&self, algorithm: HashAlgorithm) -> io::Result<(String, bool)> {
+        store_entry_hash(&self.point_path, self.link, algorithm)
+    }
+}
+
+fn commit_store_batch(
+    connection: &SqliteConnection,
+    store_dir: &str,
+    autotaggers: &Autotaggers,
+    batch: &mut Vec<PendingEntry>,
+) {
+    use schema::scan_progress;
+
+    let Some(last_entry) = batch.last() else {
+        return;
+    };
+    let last_path = path_to_db(&last_entry.path);
+
+    // Hashing and autotagging are the slow parts, so the whole batch goes through them at once before anything is written
+    let algorithm = db_hash_algorithm(connection);
+    let hashes = batch
+        .par_iter()
+        .map(|entry| (entry.hash(algorithm), autotaggers.tags(&entry.point_path)))
+        .collect::<Vec<(io::Result<(String, bool)>, TaggerResults)>>();
+
+    connection
+        .transaction::<_, diesel::result::Error, _>(|| {
+            for (entry, (hash, tagged)) in batch.drain(..).zip(hashes) {
+                // Left out of the scan cache, so the next scan tries it again
+                let hash = match hash {
+                    Ok(hash) => hash,
+                    Err(err) => {
+                        error!("Error hashing store path {:?}: {}", entry.path, err);
+                        continue;
+                    }
+                };
+
+                println!(
+                    "{:?}: {:?} -> {:?}",
+                    entry.name, entry.tags, entry.point_path
+                );
+
+                update_point_by_path(
+                    connection,
+                    entry.name,
+                    &path_to_db(&entry.point_path),
+                    hash,
+                    tagged,
+                    entry.tags,
+                    &JoinSource::Store,
+                );
+
+                cache_store_path(
+                    connection,
+                    store_dir,
+                    &path_to_db(&entry.path),
+                    entry.fingerprint,
+                )?;
+            }
+
+            // Recorded in the same transaction, so a crashed scan picks up right after the last committed batch
+            diesel::replace_into(scan_progress::table)
+                .values((
+                    scan_progress::dsl::store_dir.eq(store_dir),
+                    scan_progress::dsl::last_path.eq(last_path),
+                ))
+                .execute(connection)?;
+
+            Ok(())
+        })
+        .expect("Error committing store batch");
+}
+
+/// Reports the store paths a scan couldn't make sense of
+pub fn print_malformed(malformed: &[(PathBuf, String)]) {
+    if malformed.is_empty() {
+        return;
+    }
+
+    println!("{} store paths couldn't be imported:", malformed.len());
+
+    for (path, err) in malformed {
+        println!("  {:?}: {}", path, err);
+    }
+}
+
+/// Walks a store dir, handing over the entries that need importing, skipping those whose size, mtime and inode haven't changed since the last scan unless `full` is set
+///
+/// Rule files are fingerprinted too, and everything beneath one that changed gets imported again.
+/// Returns the paths in the scan cache that no longer exist, and the rule files to cache once everything they apply to is imported
+pub fn walk_store(
+    connection: &SqliteConnection,
+    store: &StoreDir,
+    full: bool,
+    malformed: &mut Vec<(PathBuf, String)>,
+    mut on_entry: impl FnMut(PendingEntry),
+) -> (Vec<String>, Vec<(String, Fingerprint)>) {
+    use schema::{scan_cache, scan_progress};
+
+    let store_dir = store.path();
+
+    let mut rules = StoreRules::new(store_dir);
+
+    let resume_after = scan_progress::dsl::scan_progress
+        .find(store_dir)
+        .select(scan_progress::dsl::last_path)
+        .first::<String>(connection)
+        .optional()
+        .expect("Error loading scan progress")
+        .map(|x| path_from_db(&x));
+
+    if let Some(resume_after) = &resume_after {
+        info!("Resuming scan of {:?} after {:?}", store_dir, resume_after);
+    }
+
+    let mut cached: HashMap<String, Fingerprint> = scan_cache::dsl::scan_cache
+        .filter(scan_cache::dsl::store_dir.eq(store_dir))
+        .load::<ScanCacheEntry>(connection)
+        .expect("Error loading scan cache")
+        .into_iter()
+        .map(|x| (x.path, (x.size, x.mtime, x.inode)))
+        .collect();
+
+    let mut rule_fingerprints = Vec::new();
+
+    // Dirs with rule files that changed since the last scan, which changes what everything beneath them is tagged with
+    let mut changed_rule_dirs: Vec<PathBuf> = Vec::new();
+
+    // Sorted so paths are visited in order, which is what lets an interrupted scan be resumed
+    let mut walker = walkdir::WalkDir::new(store_dir)
+        .follow_links(store.symlinks().follows())
+        .sort_by_file_name()
+        .into_iter();
+
+    while let Some(entry) = walker.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Walkdir won't descend into a link back to one of its own parents, it hands us the loop instead
+            Err(err) => {
+                match (err.path(), err.loop_ancestor()) {
+                    (Some(path), Some(ancestor)) => warn!(
+                        "Store path {:?} links back to {:?}, not following it",
+                        path, ancestor
+                    ),
+                    _ => error!("Error reading store: {}", err),
+                }
+                continue;
+            }
+        };
+
+        let path = entry.path();
+
+        // Checked before resuming, as the entries after where a scan stopped still need to see rules that changed
+        if entry.file_type().is_dir() {
+            for rule_file in RULE_FILES {
+                let rule_path = path.join(rule_file);
+                let rule_path_str = path_to_db(&rule_path);
+
+                let fingerprint = fingerprint_link(&rule_path).ok();
+
+                if cached.remove(&rule_path_str) != fingerprint {
+                    debug!("Rules in {:?} changed", rule_path);
+
+                    if !changed_rule_dirs.iter().any(|x| path.starts_with(x)) {
+                        changed_rule_dirs.push(path.to_path_buf());
+                    }
+                }
+
+                if let Some(fingerprint) = fingerprint {
+                    rule_fingerprints.push((rule_path_str, fingerprint));
+                }
+            }
+        }
+
+        if let Some(resume_after) = &resume_after {
+            if path <= resume_after.as_path() {
+                cached.remove(&path_to_db(path));
+                continue;
+            }
+        }
+
+        if rules.ignores_entry(path, entry.file_type().is_dir()) {
+            debug!("Ignoring store path {:?}", path);
+
+            if entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+
+        let Some((point_path, link)) = resolve_store_entry(store, path, entry.path_is_symlink())
+        else {
+            continue;
+        };
+
+        let (name, mut new_tags, mut tags) = match (
+            store_entry(store_dir, path, !link && !entry.file_type().is_file()),
+            rules.tags(path),
+        ) {
+            (Ok(None), _) => continue,
+            (Ok(Some((name, new_tags))), Ok(tags)) => (name, new_tags, tags),
+            (Err(err), _) | (_, Err(err)) => {
+                malformed.push((path.to_path_buf(), err));
+                continue;
+            }
+        };
+
+        let fingerprint = match store_entry_fingerprint(&point_path, link) {
+            Ok(fingerprint) => fingerprint,
+            Err(err) => {
+                error!("Error reading store path {:?}: {}", path, err);
+                continue;
+            }
+        };
+
+        // Whatever is left in here once the walk is done no longer exists
+        let cached_fingerprint = cached.remove(&path_to_db(path));
+
+        if !full
+            && cached_fingerprint == Some(fingerprint)
+            && !changed_rule_dirs.iter().any(|x| path.starts_with(x))
+        {
+            debug!("Skipping unchanged store path {:?}", path);
+            continue;
+        }
+
+        tags.append(&mut new_tags);
+
+        on_entry(PendingEntry {
+            name,
+            path: path.to_path_buf(),
+            point_path,
+            link,
+            tags,
+            fingerprint,
+        });
+    }
+
+    (cached.into_keys().collect(), rule_fingerprints)
+}
+
+/// Imports everything in a store dir that changed since the last scan, or everything if `full` is set
+pub fn load_store(
+    connection: &SqliteConnection,
+    store: &StoreDir,
+    autotaggers: &Autotaggers,
+    batch_size: usize,
+    full: bool,
+) -> Vec<(PathBuf, String)> {
+    use schema::{scan_cache, scan_progress};
+
+    let store_dir = store.path();
+
+    // Paths that couldn't be imported, reported once the whole scan is done rather than stopping it
+    let mut malformed = Vec::new();
+
+    let mut batch = Vec::new();
+
+    let (stale_paths, rule_fingerprints) =
+        walk_store(connection, store, full, &mut malformed, |entry| {
+            batch.push(entry);
+
+            if batch.len() >= batch_size {
+                commit_store_batch(connection, store_dir, autotaggers, &mut batch);
+            }
+        });
+
+    commit_store_batch(connection, store_dir, autotaggers, &mut batch);
+
+    // Chunked to stay under SQLite's limit on bound parameters
+    for stale_paths in stale_paths.chunks(500) {
+        diesel::delete(
+            scan_cache::dsl::scan_cache.filter(scan_cache::dsl::path.eq_any(stale_paths)),
+        )
+        .execute(connection)
+        .expect("Error clearing scan cache");
+    }
+
+    // Only now that everything beneath them is imported, so an interrupted scan still sees them as changed
+    connection
+        .transaction::<_, diesel::result::Error, _>(|| {
+            for (rule_path, fingerprint) in rule_fingerprints {
+                cache_store_path(connection, store_dir, &rule_path, fingerprint)?;
+            }
+
+            Ok(())
+        })
+        .expect("Error updating scan cache");
+
+    // The scan made it to the end, so the next one should start from scratch
+    diesel::delete(scan_progress::dsl::scan_progress.find(store_dir))
+        .execute(connection)
+        .expect("Error clearing scan progress");
+
+    malformed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(name: &str, tag_value: Option<&str>) -> TagEntry {
+        (
+            name.to_string(),
+            tag_value.map(|x| (x.to_string(), x.parse::<i64>().ok())),
+        )
+    }
+
+    #[test]
+    fn canonical_store_path_leaves_out_unsafe_tags() {
+        let tags = vec![
+            tag("..", None),
+            tag(".", None),
+            tag("@dir", None),
+            tag("a/b", None),
+            tag("title", Some("E = mc²")),
+            tag("x = y", Some("z")),
+            tag("up", Some("../..")),
+            tag(" padded", None),
+            tag("..", Some("fine")),
+            tag("lens", Some("..")),
+            tag("kept", None),
+        ];
+
+        assert_eq!(
+            canonical_store_path("/store", "file.txt", &tags, false),
+            PathBuf::from("/store/kept/lens = ../file.txt")
+        );
+    }
+
+    #[test]
+    fn store_paths_read_back_as_their_tags() {
+        let tags = vec![
+            tag("kept", None),
+            tag("height", Some("1080")),
+            tag("lens", Some("..")),
+            ("rating".to_string(), Some(("good".to_string(), Some(3)))),
+        ];
+
+        let path = canonical_store_path("/store", "file.txt", &tags, false);
+        let (name, read_tags) =
+            store_path_to_name_and_tags(path.strip_prefix("/store").unwrap()).unwrap();
+
+        // Sort values of numbers are worked out again when tagging, so compare them as path parts
+        let mut parts = tags
+            .iter()
+            .map(tag_entry_to_path_part)
+            .collect::<Vec<String>>();
+        let mut read_parts = read_tags
+            .iter()
+            .map(tag_entry_to_path_part)
+            .collect::<Vec<String>>();
+        parts.sort();
+        read_parts.sort();
+
+        assert_eq!(name, "file.txt");
+        assert_eq!(read_parts, parts);
+    }
+}
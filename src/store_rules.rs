@@ -0,0 +1,120 @@
+use crate::store::path_parts_to_tags;
+use crate::utils::*;
+use ignore::gitignore::Gitignore;
+use ignore::Match;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Files in a store dir that say how what's beneath them gets imported, rather than being imported themselves
+pub const RULE_FILES: [&str; 2] = ["@flat-info", ".ffsignore"];
+
+struct DirRules {
+    tags: Result<TagEntries, String>,
+    ignore: Option<Gitignore>,
+}
+
+fn load_dir_rules(dir: &Path) -> DirRules {
+    let flat_info_path = dir.join("@flat-info");
+
+    let tags = match fs::read_to_string(&flat_info_path) {
+        Ok(s) => path_parts_to_tags(
+            s.split('/')
+                .map(|x| x.trim())
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<&str>>()
+                .as_slice(),
+        )
+        .map_err(|err| format!("bad {:?}: {}", flat_info_path, err)),
+        Err(_) => Ok(vec![]),
+    };
+
+    let ignore_path = dir.join(".ffsignore");
+
+    let ignore = if ignore_path.is_file() {
+        let (ignore, err) = Gitignore::new(&ignore_path);
+
+        if let Some(err) = err {
+            warn!(
+                "Error reading {:?}, some of it is left out: {}",
+                ignore_path, err
+            );
+        }
+
+        Some(ignore)
+    } else {
+        None
+    };
+
+    DirRules { tags, ignore }
+}
+
+/// The `@flat-info` tags and `.ffsignore` rules in a store, read as they're needed
+///
+/// Both apply to everything beneath the dir they're in, so a path gets the tags of every dir above it within the store
+pub struct StoreRules {
+    store_dir: PathBuf,
+    dirs: HashMap<PathBuf, DirRules>,
+}
+
+impl StoreRules {
+    pub fn new(store_dir: &str) -> StoreRules {
+        StoreRules {
+            store_dir: PathBuf::from(store_dir),
+            dirs: HashMap::new(),
+        }
+    }
+
+    fn dir_rules(&mut self, dir: &Path) -> &DirRules {
+        self.dirs
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| load_dir_rules(dir))
+    }
+
+    /// The dirs within the store that a path is in, from the top down
+    fn dirs_above(&self, path: &Path) -> Vec<PathBuf> {
+        let mut dirs = path
+            .ancestors()
+            .skip(1)
+            .take_while(|x| x.starts_with(&self.store_dir))
+            .map(Path::to_path_buf)
+            .collect::<Vec<PathBuf>>();
+
+        dirs.reverse();
+        dirs
+    }
+
+    pub fn tags(&mut self, path: &Path) -> Result<TagEntries, String> {
+        let mut tags = Vec::new();
+
+        for dir in self.dirs_above(path) {
+            tags.extend(self.dir_rules(&dir).tags.clone()?);
+        }
+
+        Ok(tags)
+    }
+
+    /// Whether a path is ignored by its own name, for walks that already skip the contents of ignored dirs
+    pub fn ignores_entry(&mut self, path: &Path, is_dir: bool) -> bool {
+        // Like with gitignore, rules further down override the ones above them
+        for dir in self.dirs_above(path).iter().rev() {
+            if let Some(ignore) = &self.dir_rules(dir).ignore {
+                match ignore.matched(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Whether a path or any dir it's in is ignored
+    pub fn ignores_path(&mut self, path: &Path, is_dir: bool) -> bool {
+        let dirs = self.dirs_above(path);
+
+        dirs.iter().skip(1).any(|dir| self.ignores_entry(dir, true))
+            || self.ignores_entry(path, is_dir)
+    }
+}
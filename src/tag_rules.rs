@@ -0,0 +1,163 @@
+use crate::autotagger::{Autotagger, TagSource};
+use crate::store::path_parts_to_tags;
+use crate::utils::*;
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use std::os::unix::fs::MetadataExt;
+
+/// What a file has to be like for a rule or tag command to apply to it, every condition that's set has to hold
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FileMatchConfig {
+    #[serde(default)]
+    extensions: Vec<String>,
+    /// A regex for the libmagic description
+    magic: Option<String>,
+    /// A glob for the full path, where `*` doesn't cross a `/` but `**` does
+    path: Option<String>,
+    /// Something that has to exist in a dir, like `Cargo.toml` for a rust project
+    has_child: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+}
+
+/// A `tag_rules` entry in the config, giving its tags to every file that matches
+#[derive(Deserialize, Debug, Clone)]
+pub struct TagRuleConfig {
+    /// Tags written like store path parts, `name`, `name=value` or `name=value=sort`
+    tags: Vec<String>,
+    #[serde(flatten)]
+    matching: FileMatchConfig,
+}
+
+pub struct FileMatch {
+    extensions: Vec<String>,
+    magic: Option<Regex>,
+    path: Option<GlobMatcher>,
+    has_child: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+}
+
+impl FileMatch {
+    pub fn from_config(cfg: &FileMatchConfig) -> Result<FileMatch, String> {
+        let magic = match &cfg.magic {
+            Some(magic) => Some(Regex::new(magic).map_err(|err| err.to_string())?),
+            None => None,
+        };
+
+        let path = match &cfg.path {
+            Some(path) => Some(
+                GlobBuilder::new(path)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(|err| err.to_string())?
+                    .compile_matcher(),
+            ),
+            None => None,
+        };
+
+        Ok(FileMatch {
+            extensions: cfg.extensions.iter().map(|x| x.to_lowercase()).collect(),
+            magic,
+            path,
+            has_child: cfg.has_child.clone(),
+            min_size: cfg.min_size,
+            max_size: cfg.max_size,
+        })
+    }
+
+    pub fn matches(&self, source: &TagSource) -> bool {
+        if !self.extensions.is_empty()
+            && !source
+                .extension()
+                .map(|x| self.extensions.contains(&x.to_lowercase()))
+                .unwrap_or(false)
+        {
+            return false;
+        }
+
+        if let Some(magic) = &self.magic {
+            if !magic.is_match(&source.magic) {
+                return false;
+            }
+        }
+
+        if let Some(path) = &self.path {
+            if !path.is_match(source.path) {
+                return false;
+            }
+        }
+
+        if let Some(child) = &self.has_child {
+            if !source.metadata.is_dir() || source.path.join(child).symlink_metadata().is_err() {
+                return false;
+            }
+        }
+
+        let size = source.metadata.size();
+
+        self.min_size.map(|x| size >= x).unwrap_or(true)
+            && self.max_size.map(|x| size <= x).unwrap_or(true)
+    }
+}
+
+struct TagRule {
+    tags: TagEntries,
+    matching: FileMatch,
+}
+
+impl TagRule {
+    fn from_config(cfg: &TagRuleConfig) -> Result<TagRule, String> {
+        let tags = path_parts_to_tags(
+            cfg.tags
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<&str>>()
+                .as_slice(),
+        )?;
+
+        Ok(TagRule {
+            tags,
+            matching: FileMatch::from_config(&cfg.matching)?,
+        })
+    }
+}
+
+/// Runs the `tag_rules` from the config, after the built in taggers so rules can override them
+pub struct RuleTagger {
+    rules: Vec<TagRule>,
+}
+
+impl RuleTagger {
+    pub fn new(rules: &[TagRuleConfig]) -> RuleTagger {
+        let rules = rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                TagRule::from_config(rule)
+                    .unwrap_or_else(|err| panic!("tag_rules entry {} is invalid: {}", i + 1, err))
+            })
+            .collect();
+
+        RuleTagger { rules }
+    }
+}
+
+impl Autotagger for RuleTagger {
+    fn name(&self) -> &str {
+        "rules"
+    }
+
+    fn applies_to(&self, _source: &TagSource) -> bool {
+        !self.rules.is_empty()
+    }
+
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String> {
+        Ok(self
+            .rules
+            .iter()
+            .filter(|x| x.matching.matches(source))
+            .flat_map(|x| x.tags.clone())
+            .collect())
+    }
+}
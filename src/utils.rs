@@ -1,37 +1,395 @@
 use super::{schema, Join, Point, SqliteConnection, Tag, QUERY_RE};
 use blake2::{Blake2b512, Digest};
 use diesel::prelude::*;
-use std::path::Path;
-use std::{fs, io};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 
 pub type TagContent = Option<(String, Option<i64>)>;
 pub type TagEntry = (String, TagContent);
 pub type TagEntries = Vec<TagEntry>;
 
-pub fn hash_path<T: AsRef<Path>>(path: T) -> (String, bool) {
-    let md = fs::metadata(&path).unwrap();
+/// Where a point got one of its tags from, kept in its join
+#[derive(Debug, Clone, PartialEq)]
+pub enum JoinSource {
+    /// From before sources were kept
+    Unknown,
+    Autotagger(String),
+    /// The point's path in a store
+    Store,
+    /// Added by hand
+    Manual,
+}
+
+impl JoinSource {
+    pub fn to_db(&self) -> String {
+        match self {
+            JoinSource::Unknown => "unknown".to_string(),
+            JoinSource::Autotagger(name) => format!("auto:{}", name),
+            JoinSource::Store => "store".to_string(),
+            JoinSource::Manual => "manual".to_string(),
+        }
+    }
+
+    pub fn from_db(s: &str) -> JoinSource {
+        match s {
+            "store" => JoinSource::Store,
+            "manual" => JoinSource::Manual,
+            _ => match s.strip_prefix("auto:") {
+                Some(name) => JoinSource::Autotagger(name.to_string()),
+                None => JoinSource::Unknown,
+            },
+        }
+    }
+
+    /// A join keeps its source unless something ranked higher adds the same tag
+    fn rank(&self) -> u8 {
+        match self {
+            JoinSource::Unknown => 0,
+            JoinSource::Autotagger(_) => 1,
+            JoinSource::Store => 2,
+            JoinSource::Manual => 3,
+        }
+    }
+
+    pub fn outranks(&self, other: &JoinSource) -> bool {
+        self.rank() > other.rank()
+    }
+}
+
+no_arg_sql_function!(
+    last_insert_rowid,
+    diesel::sql_types::Integer,
+    "The id SQLite assigned to the most recently inserted row"
+);
+
+/// Reads a tag the way the `add` command takes them, `name` or `name=value`, with numeric values also sorting by that number
+pub fn parse_tag_arg(arg: &str) -> TagEntry {
+    let split = arg.split('=').collect::<Vec<&str>>();
+
+    let tag_content = split.get(1).map(|x| (x.to_string(), x.parse::<i64>().ok()));
+
+    (split[0].to_string(), tag_content)
+}
+
+pub fn last_insert_id(connection: &SqliteConnection) -> i32 {
+    diesel::select(last_insert_rowid)
+        .get_result::<i32>(connection)
+        .expect("error reading inserted id")
+}
+
+/// Bytes in file names that aren't valid UTF-8 are stored as code points counting up from here, at the end of the last private use plane
+const ESCAPED_BYTE_BASE: u32 = 0x10FF00;
+
+fn push_escaped_bytes(out: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        out.push(char::from_u32(ESCAPED_BYTE_BASE + *byte as u32).unwrap());
+    }
+}
+
+/// Pushes valid UTF-8, escaping any code points that are already in the escaped range byte by byte so they come back out as themselves
+fn push_valid(out: &mut String, valid: &str) {
+    for c in valid.chars() {
+        if c as u32 >= ESCAPED_BYTE_BASE {
+            push_escaped_bytes(out, c.encode_utf8(&mut [0; 4]).as_bytes());
+        } else {
+            out.push(c);
+        }
+    }
+}
+
+/// Turns a file name or path into text for the database without losing anything, see `os_str_from_db`
+pub fn os_str_to_db(s: &OsStr) -> String {
+    let mut bytes = s.as_bytes();
+    let mut out = String::with_capacity(bytes.len());
+
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                push_valid(&mut out, valid);
+                return out;
+            }
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                push_valid(&mut out, std::str::from_utf8(valid).unwrap());
+
+                let invalid_len = err.error_len().unwrap_or(rest.len());
+                push_escaped_bytes(&mut out, &rest[..invalid_len]);
+
+                bytes = &rest[invalid_len..];
+            }
+        }
+    }
+}
+
+pub fn os_str_from_db(s: &str) -> OsString {
+    let mut bytes = Vec::with_capacity(s.len());
+
+    for c in s.chars() {
+        match (c as u32).checked_sub(ESCAPED_BYTE_BASE) {
+            Some(byte) => bytes.push(byte as u8),
+            None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+
+    OsString::from_vec(bytes)
+}
+
+pub fn path_to_db<T: AsRef<Path>>(path: T) -> String {
+    os_str_to_db(path.as_ref().as_os_str())
+}
+
+pub fn path_from_db(s: &str) -> PathBuf {
+    PathBuf::from(os_str_from_db(s))
+}
+
+/// Size, mtime (in nanoseconds) and inode, used to tell whether a path needs rehashing
+pub type Fingerprint = (i64, i64, i64);
+
+pub fn fingerprint_path<T: AsRef<Path>>(path: T) -> io::Result<Fingerprint> {
+    let md = fs::metadata(&path)?;
+
+    let mtime = |md: &fs::Metadata| md.mtime() * 1_000_000_000 + md.mtime_nsec();
 
-    let mut hasher = Blake2b512::new();
+    if md.is_dir() {
+        // A directory's own mtime doesn't change when files deeper inside it do, so look at everything within
+        let mut size = 0;
+        let mut latest_mtime = mtime(&md);
 
-    let dir = md.is_dir();
-    if dir {
         for entry in walkdir::WalkDir::new(&path) {
-            let entry = entry.unwrap();
+            let entry_md = entry?.metadata()?;
+
+            if entry_md.is_file() {
+                size += entry_md.size() as i64;
+            }
+
+            latest_mtime = latest_mtime.max(mtime(&entry_md));
+        }
+
+        Ok((size, latest_mtime, md.ino() as i64))
+    } else {
+        Ok((md.size() as i64, mtime(&md), md.ino() as i64))
+    }
+}
+
+/// Like `fingerprint_path`, but for a symlink itself rather than what it points to
+pub fn fingerprint_link<T: AsRef<Path>>(path: T) -> io::Result<Fingerprint> {
+    let md = fs::symlink_metadata(&path)?;
+
+    Ok((
+        md.size() as i64,
+        md.mtime() * 1_000_000_000 + md.mtime_nsec(),
+        md.ino() as i64,
+    ))
+}
+
+/// How point contents get hashed, kept in the database so every point is hashed the same way
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Blake2b,
+    Blake3,
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake2b => "blake2b",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3 => "xxh3",
+        }
+    }
 
-            if !entry.file_type().is_file() {
-                continue;
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name {
+            "blake2b" => Some(HashAlgorithm::Blake2b),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            "xxh3" => Some(HashAlgorithm::Xxh3),
+            _ => None,
+        }
+    }
+}
+
+/// The algorithm new hashes are made with, blake2b for databases from before it could be picked
+pub fn db_hash_algorithm(connection: &SqliteConnection) -> HashAlgorithm {
+    use schema::settings;
+
+    let name = settings::table
+        .filter(settings::name.eq("hash_algorithm"))
+        .select(settings::value)
+        .first::<String>(connection)
+        .optional()
+        .expect("error loading settings");
+
+    match name {
+        Some(name) => HashAlgorithm::from_name(&name)
+            .unwrap_or_else(|| panic!("unknown hash algorithm {:?} in database", name)),
+        None => HashAlgorithm::Blake2b,
+    }
+}
+
+pub fn set_db_hash_algorithm(connection: &SqliteConnection, algorithm: HashAlgorithm) {
+    use schema::settings;
+
+    diesel::replace_into(settings::table)
+        .values((
+            settings::name.eq("hash_algorithm"),
+            settings::value.eq(algorithm.name()),
+        ))
+        .execute(connection)
+        .expect("error saving hash algorithm");
+}
+
+enum ContentHasher {
+    Blake2b(Blake2b512),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+}
+
+impl ContentHasher {
+    fn new(algorithm: HashAlgorithm) -> ContentHasher {
+        match algorithm {
+            HashAlgorithm::Blake2b => ContentHasher::Blake2b(Blake2b512::new()),
+            HashAlgorithm::Blake3 => ContentHasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Xxh3 => ContentHasher::Xxh3(Box::new(xxhash_rust::xxh3::Xxh3::new())),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            ContentHasher::Blake2b(hasher) => hasher.update(data),
+            ContentHasher::Blake3(hasher) => {
+                hasher.update(data);
             }
+            ContentHasher::Xxh3(hasher) => hasher.update(data),
+        }
+    }
 
-            let mut file = fs::File::open(entry.path()).expect("walkdir dogged the boys");
-            io::copy(&mut file, &mut hasher).expect("error reading file");
+    fn update_file(&mut self, path: &Path) -> io::Result<()> {
+        match self {
+            // blake3 can map the file and hash it across threads, which is most of the speedup for big files
+            ContentHasher::Blake3(hasher) => {
+                hasher.update_mmap_rayon(path)?;
+            }
+            _ => {
+                let mut file = fs::File::open(path)?;
+                let mut buf = vec![0; 64 * 1024];
+
+                loop {
+                    let read = file.read(&mut buf)?;
+                    if read == 0 {
+                        break;
+                    }
+
+                    self.update(&buf[..read]);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            ContentHasher::Blake2b(hasher) => hex::encode(hasher.finalize()),
+            ContentHasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+            ContentHasher::Xxh3(hasher) => hex::encode(hasher.digest128().to_be_bytes()),
         }
+    }
+}
+
+/// Hashes where a symlink points, prefixed so it can't collide with a file that happens to contain the same path
+pub fn hash_link<T: AsRef<Path>>(path: T, algorithm: HashAlgorithm) -> io::Result<(String, bool)> {
+    let target = fs::read_link(&path)?;
+
+    let mut hasher = ContentHasher::new(algorithm);
+    hasher.update(b"symlink:");
+    hasher.update(target.as_os_str().as_bytes());
+
+    Ok((hasher.finalize(), false))
+}
+
+/// Hashes a dir as a tree, each dir hashing the sorted names, modes and hashes of what's in it
+///
+/// Sorting keeps the hash the same whatever order the filesystem lists things in, and names are in there so renames inside a dir change it
+fn hash_dir(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+    let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
+
+    entries.sort_by_key(|x| x.file_name());
+
+    // Set apart from file contents, or an empty dir would hash the same as an empty file
+    let mut hasher = ContentHasher::new(algorithm);
+    hasher.update(b"dir\0");
+
+    for entry in entries {
+        let entry_path = entry.path();
+        let md = fs::symlink_metadata(&entry_path)?;
+
+        let hash = if md.is_dir() {
+            hash_dir(&entry_path, algorithm)?
+        } else if md.is_file() {
+            hash_file(&entry_path, algorithm)?
+        } else if md.file_type().is_symlink() {
+            hash_link(&entry_path, algorithm)?.0
+        } else {
+            // Sockets, fifos and devices have nothing to read, only their name and mode count
+            let mut hasher = ContentHasher::new(algorithm);
+            hasher.update(b"special\0");
+            hasher.finalize()
+        };
+
+        hasher.update(entry.file_name().as_bytes());
+        hasher.update(b"\0");
+        hasher.update(format!("{:o}", md.mode()).as_bytes());
+        hasher.update(b"\0");
+        hasher.update(hash.as_bytes());
+        hasher.update(b"\n");
+    }
+
+    Ok(hasher.finalize())
+}
+
+fn hash_file(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+    let mut hasher = ContentHasher::new(algorithm);
+    hasher.update_file(path)?;
+    Ok(hasher.finalize())
+}
+
+/// The hash of a file or dir and whether it's a dir, or why it couldn't be read, like it having been deleted in the meantime
+pub fn hash_path<T: AsRef<Path>>(path: T, algorithm: HashAlgorithm) -> io::Result<(String, bool)> {
+    let path = path.as_ref();
+    let md = fs::metadata(path)?;
+
+    if md.is_dir() {
+        Ok((hash_dir(path, algorithm)?, true))
     } else {
-        let mut file = fs::File::open(&path).expect("give me a valid path");
-        io::copy(&mut file, &mut hasher).expect("error reading file");
+        Ok((hash_file(path, algorithm)?, false))
+    }
+}
+
+/// Hashes whatever is at a point's path, `None` if there's nothing there or it can't be read
+pub fn hash_point(point: &Point, algorithm: HashAlgorithm) -> Option<String> {
+    let path = path_from_db(point.path.as_ref()?);
+
+    let hash = match fs::symlink_metadata(&path) {
+        // Only stores that keep links as links leave a point at a symlink
+        Ok(md) if md.file_type().is_symlink() => hash_link(&path, algorithm),
+        Ok(_) => hash_path(&path, algorithm),
+        Err(_) => return None,
     };
 
-    let hash = hasher.finalize();
-    (hex::encode(hash), dir)
+    match hash {
+        Ok((hash, _)) => Some(hash),
+        Err(err) => {
+            error!("Error hashing {:?}: {}", path, err);
+            None
+        }
+    }
 }
 
 pub fn get_tags_for_point(connection: &SqliteConnection, point: &Point) -> Vec<Tag> {
@@ -84,6 +442,16 @@ pub fn get_tags_by_parts(connection: &SqliteConnection, path_parts: &[&str]) ->
                         .filter(tags::dsl::sort_value.lt(sort_value))
                         .load::<Tag>(connection)
                         .expect("Error loading tags"),
+                    (Some(name), Some(">="), _, Some(Ok(sort_value))) => tags::dsl::tags
+                        .filter(tags::dsl::name.eq(name))
+                        .filter(tags::dsl::sort_value.ge(sort_value))
+                        .load::<Tag>(connection)
+                        .expect("Error loading tags"),
+                    (Some(name), Some("<="), _, Some(Ok(sort_value))) => tags::dsl::tags
+                        .filter(tags::dsl::name.eq(name))
+                        .filter(tags::dsl::sort_value.le(sort_value))
+                        .load::<Tag>(connection)
+                        .expect("Error loading tags"),
                     (Some(name), Some("!="), _, Some(Ok(sort_value))) => tags::dsl::tags
                         .filter(tags::dsl::name.eq(name))
                         .filter(tags::dsl::sort_value.ne(sort_value))
@@ -176,3 +544,59 @@ pub fn get_points_by_parts(connection: &SqliteConnection, path_parts: &[&str]) -
             .expect("Error loading points")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) -> String {
+        let db = os_str_to_db(OsStr::from_bytes(bytes));
+        assert_eq!(os_str_from_db(&db).as_bytes(), bytes);
+        db
+    }
+
+    #[test]
+    fn plain_names_are_unchanged() {
+        assert_eq!(round_trip(b""), "");
+        assert_eq!(round_trip("café.txt".as_bytes()), "café.txt");
+        assert_eq!(round_trip("\u{10FEFF}".as_bytes()), "\u{10FEFF}");
+    }
+
+    #[test]
+    fn invalid_utf8_round_trips() {
+        assert_eq!(round_trip(b"bad\xFF.txt"), "bad\u{10FFFF}.txt");
+        round_trip(b"\xC3");
+        round_trip(b"\xC3(\xA9\x80");
+        round_trip(b"\xED\xA0\x80 surrogate");
+        round_trip(&(0..=255).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn escape_range_names_round_trip() {
+        let literal = "pua\u{10FF41}.txt";
+        let db = round_trip(literal.as_bytes());
+
+        // It can't come out of the database as the single byte 0x41 it would stand for otherwise
+        assert_ne!(db, literal);
+        assert_ne!(os_str_from_db(&db).as_bytes(), b"puaA.txt");
+
+        round_trip("\u{10FF00}\u{10FFFF}".as_bytes());
+    }
+
+    #[test]
+    fn different_names_stay_different() {
+        let names: Vec<&[u8]> = vec![
+            b"A",
+            "\u{10FF41}".as_bytes(),
+            b"\xF4\x8F\xBD",
+            b"\xFF",
+            "\u{10FFFF}".as_bytes(),
+        ];
+
+        let mut dbs = names.iter().map(|x| round_trip(x)).collect::<Vec<String>>();
+        dbs.sort();
+        dbs.dedup();
+
+        assert_eq!(dbs.len(), names.len());
+    }
+}
@@ -0,0 +1,1016 @@
+use crate::autotagger::{self, flag, number, value, Autotagger, TagSource};
+use crate::utils::*;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+/// Past this the headers we want are unlikely to be what's taking the space, so they aren't read
+const HEADER_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Seconds from the Unix epoch to the ones MP4 and Matroska count from
+const MP4_EPOCH: i64 = -2_082_844_800;
+const MATROSKA_EPOCH: i64 = 978_307_200;
+
+#[derive(PartialEq)]
+enum TrackKind {
+    Video,
+    Audio,
+    Subtitle,
+    Other,
+}
+
+struct Track {
+    kind: TrackKind,
+    codec: Option<String>,
+    language: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    frame_rate: Option<f64>,
+}
+
+impl Track {
+    fn new(kind: TrackKind) -> Track {
+        Track {
+            kind,
+            codec: None,
+            language: None,
+            width: None,
+            height: None,
+            frame_rate: None,
+        }
+    }
+}
+
+/// What the container says about a video, whichever container it is
+struct VideoInfo {
+    container: &'static str,
+    /// In seconds
+    duration: Option<f64>,
+    /// As a Unix timestamp
+    created: Option<i64>,
+    tracks: Vec<Track>,
+}
+
+impl VideoInfo {
+    fn new(container: &'static str) -> VideoInfo {
+        VideoInfo {
+            container,
+            duration: None,
+            created: None,
+            tracks: Vec::new(),
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// A big endian number of `len` bytes at `at`, if there's that much there
+fn be(data: &[u8], at: usize, len: usize) -> Option<u64> {
+    let bytes = data.get(at..at.checked_add(len)?)?;
+    Some(bytes.iter().fold(0, |n, x| n << 8 | *x as u64))
+}
+
+fn le(data: &[u8], at: usize, len: usize) -> Option<u64> {
+    let bytes = data.get(at..at.checked_add(len)?)?;
+    Some(bytes.iter().rev().fold(0, |n, x| n << 8 | *x as u64))
+}
+
+/// The year, month, day, hour, minute and second of a Unix timestamp, in UTC
+fn civil_time(timestamp: i64) -> (i64, u32, u32, u32, u32, u32) {
+    let days = timestamp.div_euclid(86400);
+    let seconds = timestamp.rem_euclid(86400) as u32;
+
+    // Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+    )
+}
+
+/// The children of an MP4 box, as their type and contents
+fn mp4_boxes(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut boxes = Vec::new();
+    let mut at = 0;
+
+    while let (Some(size), Some(box_type)) = (be(data, at, 4), data.get(at + 4..at + 8)) {
+        let (start, end) = match size {
+            0 => (at + 8, data.len()),
+            1 => match be(data, at + 8, 8) {
+                Some(size) => (at + 16, at.saturating_add(size as usize)),
+                None => break,
+            },
+            size => (at + 8, at.saturating_add(size as usize)),
+        };
+
+        if end < start || end > data.len() {
+            break;
+        }
+
+        boxes.push((box_type, &data[start..end]));
+        at = end;
+    }
+
+    boxes
+}
+
+fn mp4_child<'a>(data: &'a [u8], path: &[&[u8]]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, name| {
+        mp4_boxes(data)
+            .into_iter()
+            .find(|(box_type, _)| box_type == name)
+            .map(|(_, contents)| contents)
+    })
+}
+
+fn mp4_codec(fourcc: &[u8]) -> String {
+    match fourcc {
+        b"avc1" | b"avc3" => "h264".to_string(),
+        b"hvc1" | b"hev1" => "hevc".to_string(),
+        b"av01" => "av1".to_string(),
+        b"vp08" => "vp8".to_string(),
+        b"vp09" => "vp9".to_string(),
+        b"mp4v" => "mpeg4".to_string(),
+        b"apch" | b"apcn" | b"apcs" | b"apco" | b"ap4h" | b"ap4x" => "prores".to_string(),
+        b"jpeg" | b"mjpa" | b"mjpb" => "mjpeg".to_string(),
+        b"mp4a" => "aac".to_string(),
+        b".mp3" => "mp3".to_string(),
+        b"ac-3" => "ac3".to_string(),
+        b"ec-3" => "eac3".to_string(),
+        b"Opus" => "opus".to_string(),
+        b"fLaC" => "flac".to_string(),
+        b"alac" => "alac".to_string(),
+        b"lpcm" | b"sowt" | b"twos" | b"in24" | b"in32" | b"fl32" => "pcm".to_string(),
+        _ => String::from_utf8_lossy(fourcc).trim().to_lowercase(),
+    }
+}
+
+fn mp4_track(trak: &[u8]) -> Option<Track> {
+    let mdia = mp4_child(trak, &[b"mdia"])?;
+
+    let kind = match mp4_child(mdia, &[b"hdlr"]).and_then(|x| x.get(8..12))? {
+        b"vide" => TrackKind::Video,
+        b"soun" => TrackKind::Audio,
+        b"sbtl" | b"subt" | b"text" | b"clcp" => TrackKind::Subtitle,
+        _ => TrackKind::Other,
+    };
+
+    let mut track = Track::new(kind);
+
+    let mdhd = mp4_child(mdia, &[b"mdhd"])?;
+    let (timescale, language) = match mdhd.first()? {
+        1 => (be(mdhd, 20, 4)?, be(mdhd, 32, 2)?),
+        _ => (be(mdhd, 12, 4)?, be(mdhd, 20, 2)?),
+    };
+
+    // Packed ISO 639-2 letters, smaller values are old Macintosh language codes
+    if language >= 0x400 {
+        let code = [10, 5, 0]
+            .iter()
+            .map(|shift| ((language >> shift & 0x1f) as u8 + 0x60) as char)
+            .collect::<String>();
+
+        if code.chars().all(|x| x.is_ascii_lowercase()) && code != "und" {
+            track.language = Some(code);
+        }
+    }
+
+    let stbl = mp4_child(mdia, &[b"minf", b"stbl"])?;
+
+    if let Some(entry) = mp4_child(stbl, &[b"stsd"]).and_then(|x| x.get(8..)) {
+        if let Some(fourcc) = entry.get(4..8) {
+            track.codec = Some(mp4_codec(fourcc));
+        }
+
+        if track.kind == TrackKind::Video {
+            track.width = be(entry, 32, 2).map(|x| x as u32).filter(|x| *x != 0);
+            track.height = be(entry, 34, 2).map(|x| x as u32).filter(|x| *x != 0);
+        }
+    }
+
+    // The sample durations add up to the track's length in its timescale, and there's a sample per frame
+    if track.kind == TrackKind::Video {
+        if let Some(stts) = mp4_child(stbl, &[b"stts"]) {
+            let entries = be(stts, 4, 4).unwrap_or(0) as usize;
+
+            let (frames, time) = (0..entries)
+                .map_while(|i| Some((be(stts, 8 + i * 8, 4)?, be(stts, 12 + i * 8, 4)?)))
+                .fold((0u64, 0u64), |(frames, time), (count, delta)| {
+                    (
+                        frames.saturating_add(count),
+                        time.saturating_add(count.saturating_mul(delta)),
+                    )
+                });
+
+            if frames > 0 && time > 0 {
+                track.frame_rate = Some(frames as f64 * timescale as f64 / time as f64);
+            }
+        }
+    }
+
+    Some(track)
+}
+
+fn mp4_info(file: &mut fs::File) -> io::Result<VideoInfo> {
+    let len = file.metadata()?.len();
+
+    let mut container = "mp4";
+    let mut moov = None;
+    let mut at = 0;
+
+    // The top level boxes are walked without reading them, the media data in `mdat` can come before `moov`
+    while at + 8 <= len {
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(at))?;
+        file.read_exact(&mut header[..8])?;
+
+        let (header_len, size) = match be(&header, 0, 4).unwrap() {
+            0 => (8, len - at),
+            1 => {
+                file.read_exact(&mut header[8..])?;
+                (16, be(&header, 8, 8).unwrap())
+            }
+            size => (8, size),
+        };
+
+        // The 64 bit size is whatever the file says, so it could point anywhere
+        let Some(end) = at
+            .checked_add(size)
+            .filter(|x| size >= header_len && *x <= len)
+        else {
+            break;
+        };
+
+        match &header[4..8] {
+            b"ftyp" => {
+                let mut brand = [0; 4];
+                file.read_exact(&mut brand)?;
+
+                if &brand == b"qt  " {
+                    container = "mov";
+                }
+            }
+            b"moov" => {
+                if size > HEADER_LIMIT {
+                    return Err(invalid("moov box is too big"));
+                }
+
+                let mut data = vec![0; (size - header_len) as usize];
+                file.read_exact(&mut data)?;
+                moov = Some(data);
+                break;
+            }
+            _ => {}
+        }
+
+        at = end;
+    }
+
+    let moov = moov.ok_or_else(|| invalid("no moov box"))?;
+
+    let mut info = VideoInfo::new(container);
+
+    if let Some(mvhd) = mp4_child(&moov, &[b"mvhd"]) {
+        let (created, timescale, duration) = match mvhd.first() {
+            Some(1) => (be(mvhd, 4, 8), be(mvhd, 20, 4), be(mvhd, 24, 8)),
+            _ => (be(mvhd, 4, 4), be(mvhd, 12, 4), be(mvhd, 16, 4)),
+        };
+
+        // Plenty of muxers leave the creation time at zero
+        info.created = created
+            .filter(|x| *x != 0)
+            .and_then(|x| i64::try_from(x).ok()?.checked_add(MP4_EPOCH));
+
+        if let (Some(timescale), Some(duration)) = (timescale.filter(|x| *x != 0), duration) {
+            info.duration = Some(duration as f64 / timescale as f64);
+        }
+    }
+
+    info.tracks = mp4_boxes(&moov)
+        .into_iter()
+        .filter(|(box_type, _)| *box_type == b"trak")
+        .filter_map(|(_, trak)| mp4_track(trak))
+        .collect();
+
+    Ok(info)
+}
+
+mod ebml {
+    pub const EBML: u64 = 0x1A45DFA3;
+    pub const DOC_TYPE: u64 = 0x4282;
+    pub const SEGMENT: u64 = 0x18538067;
+    pub const INFO: u64 = 0x1549A966;
+    pub const TIMESTAMP_SCALE: u64 = 0x2AD7B1;
+    pub const DURATION: u64 = 0x4489;
+    pub const DATE_UTC: u64 = 0x4461;
+    pub const TRACKS: u64 = 0x1654AE6B;
+    pub const TRACK_ENTRY: u64 = 0xAE;
+    pub const TRACK_TYPE: u64 = 0x83;
+    pub const CODEC_ID: u64 = 0x86;
+    pub const LANGUAGE: u64 = 0x22B59C;
+    pub const LANGUAGE_BCP47: u64 = 0x22B59D;
+    pub const DEFAULT_DURATION: u64 = 0x23E383;
+    pub const VIDEO: u64 = 0xE0;
+    pub const PIXEL_WIDTH: u64 = 0xB0;
+    pub const PIXEL_HEIGHT: u64 = 0xBA;
+    pub const CLUSTER: u64 = 0x1F43B675;
+}
+
+/// An EBML variable length number and how many bytes it took, IDs keep their length marker but sizes don't
+fn ebml_vint(data: &[u8], at: usize, keep_marker: bool) -> Option<(u64, usize)> {
+    let first = *data.get(at)?;
+    let len = first.leading_zeros() as usize + 1;
+
+    if len > 8 {
+        return None;
+    }
+
+    let mut n = be(data, at, len)?;
+
+    if !keep_marker {
+        n &= (1 << (7 * len)) - 1;
+    }
+
+    Some((n, len))
+}
+
+/// A size of all ones means it runs until its parent ends
+fn ebml_unknown_size(size: u64, len: usize) -> bool {
+    size == (1 << (7 * len)) - 1
+}
+
+/// The children of an EBML element, as their ID and contents
+fn ebml_elements(data: &[u8]) -> Vec<(u64, &[u8])> {
+    let mut elements = Vec::new();
+    let mut at = 0;
+
+    while let Some((id, id_len)) = ebml_vint(data, at, true) {
+        let Some((size, size_len)) = ebml_vint(data, at + id_len, false) else {
+            break;
+        };
+
+        let start = at + id_len + size_len;
+        let end = if ebml_unknown_size(size, size_len) {
+            data.len()
+        } else {
+            start.saturating_add(size as usize)
+        };
+
+        if end > data.len() {
+            break;
+        }
+
+        elements.push((id, &data[start..end]));
+        at = end;
+    }
+
+    elements
+}
+
+fn ebml_child(data: &[u8], id: u64) -> Option<&[u8]> {
+    ebml_elements(data)
+        .into_iter()
+        .find(|(element_id, _)| *element_id == id)
+        .map(|(_, contents)| contents)
+}
+
+fn ebml_uint(data: &[u8], id: u64) -> Option<u64> {
+    ebml_child(data, id).and_then(|x| be(x, 0, x.len().min(8)))
+}
+
+fn ebml_string(data: &[u8], id: u64) -> Option<String> {
+    ebml_child(data, id).map(|x| {
+        String::from_utf8_lossy(x)
+            .trim_end_matches(char::from(0))
+            .to_string()
+    })
+}
+
+fn ebml_float(data: &[u8], id: u64) -> Option<f64> {
+    let bytes = ebml_child(data, id)?;
+
+    match bytes.len() {
+        4 => Some(f32::from_bits(be(bytes, 0, 4)? as u32) as f64),
+        8 => Some(f64::from_bits(be(bytes, 0, 8)?)),
+        _ => None,
+    }
+}
+
+fn matroska_codec(codec_id: &str) -> String {
+    let codec = codec_id.split_once('_').map(|x| x.1).unwrap_or(codec_id);
+
+    match codec {
+        "MPEG4/ISO/AVC" => "h264".to_string(),
+        "MPEGH/ISO/HEVC" => "hevc".to_string(),
+        "MPEG4/ISO/SP" | "MPEG4/ISO/ASP" | "MPEG4/ISO/AP" => "mpeg4".to_string(),
+        "MPEG1" | "MPEG2" => codec.to_lowercase(),
+        "MPEG/L3" => "mp3".to_string(),
+        "MPEG/L2" => "mp2".to_string(),
+        "MS/VFW/FOURCC" => "vfw".to_string(),
+        x if x.starts_with("AAC") => "aac".to_string(),
+        x if x.starts_with("PCM/") => "pcm".to_string(),
+        x if x.starts_with("TRUEHD") => "truehd".to_string(),
+        x => x.to_lowercase().replace('/', "-"),
+    }
+}
+
+fn matroska_track(entry: &[u8]) -> Track {
+    let kind = match ebml_uint(entry, ebml::TRACK_TYPE) {
+        Some(1) => TrackKind::Video,
+        Some(2) => TrackKind::Audio,
+        Some(17) => TrackKind::Subtitle,
+        _ => TrackKind::Other,
+    };
+
+    let mut track = Track::new(kind);
+
+    track.codec = ebml_string(entry, ebml::CODEC_ID).map(|x| matroska_codec(&x));
+
+    // English is the default when a track doesn't say
+    track.language = ebml_string(entry, ebml::LANGUAGE_BCP47)
+        .or_else(|| ebml_string(entry, ebml::LANGUAGE))
+        .or_else(|| Some("eng".to_string()))
+        .filter(|x| !x.is_empty() && x != "und");
+
+    if let Some(video) = ebml_child(entry, ebml::VIDEO) {
+        track.width = ebml_uint(video, ebml::PIXEL_WIDTH).map(|x| x as u32);
+        track.height = ebml_uint(video, ebml::PIXEL_HEIGHT).map(|x| x as u32);
+    }
+
+    // Nanoseconds per frame
+    if track.kind == TrackKind::Video {
+        track.frame_rate = ebml_uint(entry, ebml::DEFAULT_DURATION)
+            .filter(|x| *x != 0)
+            .map(|x| 1_000_000_000.0 / x as f64);
+    }
+
+    track
+}
+
+/// The next element's ID, size and where its contents start, when reading from a file
+fn read_ebml_header(file: &mut BufReader<fs::File>) -> io::Result<(u64, Option<u64>, u64)> {
+    let mut data = [0; 16];
+    let at = file.stream_position()?;
+    let read = file.read(&mut data)?;
+
+    let (id, id_len) = ebml_vint(&data[..read], 0, true).ok_or_else(|| invalid("bad EBML ID"))?;
+    let (size, size_len) =
+        ebml_vint(&data[..read], id_len, false).ok_or_else(|| invalid("bad EBML size"))?;
+
+    let start = at + (id_len + size_len) as u64;
+    file.seek(SeekFrom::Start(start))?;
+
+    if ebml_unknown_size(size, size_len) {
+        Ok((id, None, start))
+    } else {
+        Ok((id, Some(size), start))
+    }
+}
+
+fn read_ebml_contents(file: &mut BufReader<fs::File>, size: u64) -> io::Result<Vec<u8>> {
+    if size > HEADER_LIMIT {
+        return Err(invalid("EBML element is too big"));
+    }
+
+    let mut data = vec![0; size as usize];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn matroska_info(file: fs::File) -> io::Result<VideoInfo> {
+    let len = file.metadata()?.len();
+    let mut file = BufReader::new(file);
+
+    let (id, size, _) = read_ebml_header(&mut file)?;
+    if id != ebml::EBML {
+        return Err(invalid("no EBML header"));
+    }
+
+    let header = read_ebml_contents(&mut file, size.ok_or_else(|| invalid("bad EBML header"))?)?;
+
+    let mut info = VideoInfo::new(match ebml_string(&header, ebml::DOC_TYPE).as_deref() {
+        Some("webm") => "webm",
+        _ => "mkv",
+    });
+
+    let (id, _, mut at) = read_ebml_header(&mut file)?;
+    if id != ebml::SEGMENT {
+        return Err(invalid("no Matroska segment"));
+    }
+
+    let mut segment_info = None;
+    let mut tracks = None;
+
+    // Clusters are most of the file, and the elements we want come before them in just about every file
+    while at < len && (segment_info.is_none() || tracks.is_none()) {
+        file.seek(SeekFrom::Start(at))?;
+        let (id, size, start) = read_ebml_header(&mut file)?;
+
+        let Some(size) = size else {
+            break;
+        };
+
+        match id {
+            ebml::INFO => segment_info = Some(read_ebml_contents(&mut file, size)?),
+            ebml::TRACKS => tracks = Some(read_ebml_contents(&mut file, size)?),
+            ebml::CLUSTER if segment_info.is_some() || tracks.is_some() => break,
+            _ => {}
+        }
+
+        at = start + size;
+    }
+
+    if let Some(segment_info) = segment_info {
+        let scale = ebml_uint(&segment_info, ebml::TIMESTAMP_SCALE).unwrap_or(1_000_000);
+
+        info.duration = ebml_float(&segment_info, ebml::DURATION).map(|x| x * scale as f64 / 1e9);
+
+        // Signed nanoseconds since 2001
+        info.created = ebml_uint(&segment_info, ebml::DATE_UTC)
+            .map(|x| (x as i64).div_euclid(1_000_000_000) + MATROSKA_EPOCH);
+    }
+
+    if let Some(tracks) = tracks {
+        info.tracks = ebml_elements(&tracks)
+            .into_iter()
+            .filter(|(id, _)| *id == ebml::TRACK_ENTRY)
+            .map(|(_, entry)| matroska_track(entry))
+            .collect();
+    }
+
+    Ok(info)
+}
+
+/// The chunks in a RIFF list, as their ID and contents, `LIST` chunks have their list type at the start
+fn riff_chunks(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut chunks = Vec::new();
+    let mut at = 0;
+
+    while let (Some(id), Some(size)) = (data.get(at..at + 4), le(data, at + 4, 4)) {
+        let start = at + 8;
+        let end = start.saturating_add(size as usize);
+
+        if end > data.len() {
+            break;
+        }
+
+        chunks.push((id, &data[start..end]));
+
+        // Chunks are padded to an even length
+        at = end + (end & 1);
+    }
+
+    chunks
+}
+
+fn riff_lists<'a>(data: &'a [u8], list_type: &[u8]) -> Vec<&'a [u8]> {
+    riff_chunks(data)
+        .into_iter()
+        .filter(|(id, contents)| *id == b"LIST" && contents.get(0..4) == Some(list_type))
+        .map(|(_, contents)| &contents[4..])
+        .collect()
+}
+
+fn riff_chunk<'a>(data: &'a [u8], chunk_id: &[u8]) -> Option<&'a [u8]> {
+    riff_chunks(data)
+        .into_iter()
+        .find(|(id, _)| *id == chunk_id)
+        .map(|(_, contents)| contents)
+}
+
+fn avi_video_codec(fourcc: &[u8]) -> String {
+    match &fourcc.to_ascii_uppercase()[..] {
+        b"H264" | b"X264" | b"AVC1" => "h264".to_string(),
+        b"HEVC" | b"H265" | b"X265" | b"HVC1" => "hevc".to_string(),
+        b"XVID" | b"DIVX" | b"DX50" | b"FMP4" | b"MP4V" => "mpeg4".to_string(),
+        b"MJPG" => "mjpeg".to_string(),
+        _ => String::from_utf8_lossy(fourcc)
+            .trim_matches(char::from(0))
+            .trim()
+            .to_lowercase(),
+    }
+}
+
+fn avi_audio_codec(format_tag: u64) -> String {
+    match format_tag {
+        0x0001 => "pcm".to_string(),
+        0x0055 => "mp3".to_string(),
+        0x0050 => "mp2".to_string(),
+        0x00FF | 0x1610 => "aac".to_string(),
+        0x2000 => "ac3".to_string(),
+        0x2001 => "dts".to_string(),
+        0x0161 | 0x0162 => "wma".to_string(),
+        x => format!("0x{:04x}", x),
+    }
+}
+
+fn avi_info(file: &mut fs::File) -> io::Result<VideoInfo> {
+    let mut header = [0; 12];
+    file.read_exact(&mut header)?;
+
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"AVI " {
+        return Err(invalid("not an AVI file"));
+    }
+
+    // `hdrl` is the first list in the file and has everything we want
+    let mut chunk_header = [0; 12];
+    file.read_exact(&mut chunk_header)?;
+
+    if &chunk_header[0..4] != b"LIST" || &chunk_header[8..12] != b"hdrl" {
+        return Err(invalid("no hdrl list"));
+    }
+
+    let size = le(&chunk_header, 4, 4).unwrap().saturating_sub(4);
+    if size > HEADER_LIMIT {
+        return Err(invalid("hdrl list is too big"));
+    }
+
+    let mut hdrl = vec![0; size as usize];
+    file.read_exact(&mut hdrl)?;
+
+    let mut info = VideoInfo::new("avi");
+
+    let avih = riff_chunk(&hdrl, b"avih").ok_or_else(|| invalid("no avih chunk"))?;
+    let micros_per_frame = le(avih, 0, 4).unwrap_or(0);
+
+    // The main header only counts the frames in the first RIFF chunk of files past 1GB, OpenDML has them all
+    let frames = riff_lists(&hdrl, b"odml")
+        .first()
+        .and_then(|x| riff_chunk(x, b"dmlh"))
+        .and_then(|x| le(x, 0, 4))
+        .or_else(|| le(avih, 16, 4))
+        .unwrap_or(0);
+
+    if micros_per_frame != 0 && frames != 0 {
+        info.duration = Some(frames as f64 * micros_per_frame as f64 / 1e6);
+    }
+
+    for strl in riff_lists(&hdrl, b"strl") {
+        let (Some(strh), Some(strf)) = (riff_chunk(strl, b"strh"), riff_chunk(strl, b"strf"))
+        else {
+            continue;
+        };
+
+        let mut track = match strh.get(0..4) {
+            Some(b"vids") => {
+                let mut track = Track::new(TrackKind::Video);
+                track.codec = strf.get(16..20).map(avi_video_codec);
+                track.width = le(strf, 4, 4).map(|x| x as u32);
+                // Negative for images stored top down
+                track.height = le(strf, 8, 4).map(|x| (x as u32 as i32).unsigned_abs());
+
+                if let (Some(scale), Some(rate)) = (le(strh, 20, 4), le(strh, 24, 4)) {
+                    if scale != 0 && rate != 0 {
+                        track.frame_rate = Some(rate as f64 / scale as f64);
+                    }
+                }
+
+                track
+            }
+            Some(b"auds") => {
+                let mut track = Track::new(TrackKind::Audio);
+                track.codec = le(strf, 0, 2).map(avi_audio_codec);
+                track
+            }
+            Some(b"txts") => Track::new(TrackKind::Subtitle),
+            _ => Track::new(TrackKind::Other),
+        };
+
+        track.codec = track.codec.filter(|x| !x.is_empty());
+        info.tracks.push(track);
+    }
+
+    Ok(info)
+}
+
+/// Picks the reader for a file from its first bytes, rather than trusting its extension
+fn video_info(path: &std::path::Path) -> io::Result<VideoInfo> {
+    let mut file = fs::File::open(path)?;
+
+    let mut start = [0; 12];
+    let read = file.read(&mut start)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    match &start[..read] {
+        [_, _, _, _, b'f', b't', b'y', b'p', ..]
+        | [_, _, _, _, b'm', b'o', b'o', b'v', ..]
+        | [_, _, _, _, b'w', b'i', b'd', b'e', ..]
+        | [_, _, _, _, b'm', b'd', b'a', b't', ..] => mp4_info(&mut file),
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => matroska_info(file),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'A', b'V', b'I', b' '] => avi_info(&mut file),
+        _ => Err(invalid("not a video container we can read")),
+    }
+}
+
+fn frame_rate_text(frame_rate: f64) -> String {
+    let text = format!("{:.3}", frame_rate);
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+fn info_tags(info: &VideoInfo) -> TagEntries {
+    let mut tags = vec![value("type", info.container)];
+
+    let video = info.tracks.iter().find(|x| x.kind == TrackKind::Video);
+
+    if let Some(video) = video {
+        tags.push(flag("video"));
+
+        if let (Some(width), Some(height)) = (video.width, video.height) {
+            tags.push(value("resolution", &format!("{}x{}", width, height)));
+            tags.push(number("width", &width.to_string()));
+            tags.push(number("height", &height.to_string()));
+        }
+
+        if let Some(frame_rate) = video.frame_rate.filter(|x| x.is_finite() && *x > 0.0) {
+            tags.push((
+                "frame_rate".to_string(),
+                Some((frame_rate_text(frame_rate), Some(frame_rate.round() as i64))),
+            ));
+        }
+    }
+
+    if let Some(duration) = info.duration.filter(|x| x.is_finite() && *x > 0.0) {
+        tags.push(autotagger::duration(duration));
+    }
+
+    if let Some(created) = info.created {
+        let (year, month, day, hour, minute, second) = civil_time(created);
+        tags.push(autotagger::date(year, month, day, hour, minute, second));
+    }
+
+    for track in &info.tracks {
+        let codec_tag = match track.kind {
+            TrackKind::Video => "video_codec",
+            TrackKind::Audio => "audio_codec",
+            _ => continue,
+        };
+
+        if let Some(codec) = &track.codec {
+            tags.push(value(codec_tag, codec));
+        }
+    }
+
+    for track in &info.tracks {
+        let language_tag = match track.kind {
+            TrackKind::Audio => "audio_language",
+            TrackKind::Subtitle => "subtitle_language",
+            _ => continue,
+        };
+
+        if let Some(language) = &track.language {
+            tags.push(value(language_tag, language));
+        }
+    }
+
+    let subtitles = info
+        .tracks
+        .iter()
+        .filter(|x| x.kind == TrackKind::Subtitle)
+        .count();
+
+    if subtitles > 0 {
+        tags.push(number("subtitles", &subtitles.to_string()));
+    }
+
+    tags
+}
+
+/// Resolution, duration, codecs, frame rate, languages and creation dates from MP4, QuickTime, Matroska, WebM and AVI files
+pub struct VideoTagger;
+
+impl Autotagger for VideoTagger {
+    fn name(&self) -> &str {
+        "video"
+    }
+
+    fn applies_to(&self, source: &TagSource) -> bool {
+        source.magic_parts().any(|x| {
+            x.starts_with("MP4 ")
+                || x.starts_with("Apple QuickTime")
+                || x.starts_with("Matroska data")
+                || x == "WebM"
+                || x == "AVI"
+        }) || source
+            .extension()
+            .map(|x| {
+                ["mp4", "m4v", "mov", "mkv", "webm", "avi"].contains(&x.to_lowercase().as_str())
+            })
+            .unwrap_or(false)
+    }
+
+    fn tags(&self, source: &TagSource) -> Result<TagEntries, String> {
+        video_info(source.path)
+            .map(|info| info_tags(&info))
+            .map_err(|err| format!("error reading video: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(box_type: &[u8], contents: &[u8]) -> Vec<u8> {
+        let mut data = ((contents.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(contents);
+        data
+    }
+
+    fn mp4_file(name: &str, data: &[u8]) -> fs::File {
+        let path = std::env::temp_dir().join(format!("ffs-video-{}-{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        let file = fs::File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        file
+    }
+
+    #[test]
+    fn civil_time_dates() {
+        assert_eq!(civil_time(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(civil_time(951_827_696), (2000, 2, 29, 12, 34, 56));
+        assert_eq!(civil_time(-1), (1969, 12, 31, 23, 59, 59));
+
+        // Nothing a file can put in a timestamp should make it panic
+        civil_time(i64::MAX);
+        civil_time(i64::MIN);
+    }
+
+    #[test]
+    fn mp4_boxes_in_order() {
+        let mut data = mp4_box(b"ftyp", b"isom");
+        data.extend(mp4_box(b"free", b""));
+
+        assert_eq!(
+            mp4_boxes(&data),
+            vec![(&b"ftyp"[..], &b"isom"[..]), (&b"free"[..], &b""[..])]
+        );
+    }
+
+    #[test]
+    fn mp4_boxes_size_zero_runs_to_end() {
+        let data = [&[0, 0, 0, 0][..], b"mdat", b"rest"].concat();
+        assert_eq!(mp4_boxes(&data), vec![(&b"mdat"[..], &b"rest"[..])]);
+    }
+
+    #[test]
+    fn mp4_boxes_truncated() {
+        assert!(mp4_boxes(b"").is_empty());
+        assert!(mp4_boxes(&[0, 0, 0]).is_empty());
+        assert!(mp4_boxes(&[0, 0, 0, 16, b'f', b'r']).is_empty());
+
+        // The second box says it's longer than what's left, the first is still there
+        let mut data = mp4_box(b"free", b"");
+        data.extend_from_slice(&[0, 0, 0, 64, b'm', b'o', b'o', b'v', 1, 2]);
+        assert_eq!(mp4_boxes(&data), vec![(&b"free"[..], &b""[..])]);
+
+        // Sizes smaller than the header would never move forward
+        assert!(mp4_boxes(&[0, 0, 0, 4, b'f', b'r', b'e', b'e']).is_empty());
+    }
+
+    #[test]
+    fn mp4_boxes_oversized() {
+        let data = [&[0, 0, 0, 1][..], b"free", &u64::MAX.to_be_bytes()].concat();
+        assert!(mp4_boxes(&data).is_empty());
+
+        let data = [&u32::MAX.to_be_bytes()[..], b"free"].concat();
+        assert!(mp4_boxes(&data).is_empty());
+    }
+
+    #[test]
+    fn mp4_info_oversized_box() {
+        let data = [
+            &mp4_box(b"ftyp", b"isom")[..],
+            &[0, 0, 0, 1],
+            b"mdat",
+            &u64::MAX.to_be_bytes(),
+        ]
+        .concat();
+
+        let err = mp4_info(&mut mp4_file("oversized", &data)).err();
+        assert_eq!(err.map(|x| x.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn mp4_info_oversized_creation_time() {
+        // Version 1 mvhd, with the biggest creation time there is
+        let mut mvhd = vec![1, 0, 0, 0];
+        mvhd.extend_from_slice(&u64::MAX.to_be_bytes());
+        mvhd.extend_from_slice(&0u64.to_be_bytes());
+        mvhd.extend_from_slice(&1000u32.to_be_bytes());
+        mvhd.extend_from_slice(&5000u64.to_be_bytes());
+
+        let data = mp4_box(b"moov", &mp4_box(b"mvhd", &mvhd));
+        let info = mp4_info(&mut mp4_file("created", &data)).unwrap();
+
+        assert_eq!(info.created, None);
+        assert_eq!(info.duration, Some(5.0));
+    }
+
+    #[test]
+    fn ebml_vint_lengths() {
+        assert_eq!(ebml_vint(&[0x81], 0, false), Some((1, 1)));
+        assert_eq!(ebml_vint(&[0x81], 0, true), Some((0x81, 1)));
+        assert_eq!(ebml_vint(&[0x40, 0x02], 0, false), Some((2, 2)));
+        assert_eq!(
+            ebml_vint(&[0x1A, 0x45, 0xDF, 0xA3], 0, true),
+            Some((ebml::EBML, 4))
+        );
+        assert_eq!(
+            ebml_vint(&[0x01; 8], 0, false),
+            Some((0x01_0101_0101_0101, 8))
+        );
+    }
+
+    #[test]
+    fn ebml_vint_truncated() {
+        assert_eq!(ebml_vint(&[], 0, false), None);
+        assert_eq!(ebml_vint(&[0x81], 1, false), None);
+        assert_eq!(ebml_vint(&[0x40], 0, false), None);
+        assert_eq!(ebml_vint(&[0x01, 0, 0], 0, false), None);
+
+        // A zero first byte would need more than 8 bytes
+        assert_eq!(ebml_vint(&[0; 9], 0, false), None);
+    }
+
+    #[test]
+    fn ebml_elements_in_order() {
+        let data = [0x83, 0x81, 0x01, 0x86, 0x82, b'V', b'P'];
+
+        assert_eq!(
+            ebml_elements(&data),
+            vec![
+                (ebml::TRACK_TYPE, &[0x01][..]),
+                (ebml::CODEC_ID, &b"VP"[..])
+            ]
+        );
+    }
+
+    #[test]
+    fn ebml_elements_unknown_size_runs_to_end() {
+        let data = [0xAE, 0xFF, 0x83, 0x81, 0x01];
+        assert_eq!(
+            ebml_elements(&data),
+            vec![(ebml::TRACK_ENTRY, &[0x83, 0x81, 0x01][..])]
+        );
+    }
+
+    #[test]
+    fn ebml_elements_truncated_and_oversized() {
+        assert!(ebml_elements(&[]).is_empty());
+        assert!(ebml_elements(&[0x83]).is_empty());
+        assert!(ebml_elements(&[0x83, 0x84, 0x01]).is_empty());
+
+        // Biggest known size there is, 2^56 - 2
+        let data = [0x83, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE, 0x00];
+        assert!(ebml_elements(&data).is_empty());
+
+        // What comes before a bad element is kept
+        let data = [0x83, 0x81, 0x01, 0x86, 0x90];
+        assert_eq!(ebml_elements(&data), vec![(ebml::TRACK_TYPE, &[0x01][..])]);
+    }
+
+    #[test]
+    fn riff_chunks_padded() {
+        let data = [
+            &b"strh"[..],
+            &[3, 0, 0, 0],
+            b"abc",
+            &[0],
+            b"strf",
+            &[0, 0, 0, 0],
+        ]
+        .concat();
+
+        assert_eq!(
+            riff_chunks(&data),
+            vec![(&b"strh"[..], &b"abc"[..]), (&b"strf"[..], &b""[..])]
+        );
+    }
+
+    #[test]
+    fn riff_chunks_truncated_and_oversized() {
+        assert!(riff_chunks(b"").is_empty());
+        assert!(riff_chunks(b"strh").is_empty());
+        assert!(riff_chunks(&[&b"strh"[..], &[8, 0, 0, 0], b"abc"].concat()).is_empty());
+        assert!(riff_chunks(&[&b"strh"[..], &u32::MAX.to_le_bytes(), b"abc"].concat()).is_empty());
+
+        // A padding byte missing at the very end doesn't lose the chunk
+        let data = [&b"strh"[..], &[3, 0, 0, 0], b"abc"].concat();
+        assert_eq!(riff_chunks(&data), vec![(&b"strh"[..], &b"abc"[..])]);
+    }
+}
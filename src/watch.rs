@@ -0,0 +1,283 @@
+use super::{configure_connection, FfsConfig, SqliteConnection};
+use crate::autotagger::Autotaggers;
+use crate::store::*;
+use crate::store_rules::*;
+use diesel::prelude::*;
+use notify::event::{AccessKind, AccessMode, EventKind, ModifyKind, RenameMode};
+use notify::{Event, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// How long the stores have to be quiet before a burst of events is handled
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+enum StoreChange {
+    Changed(PathBuf),
+    Moved(PathBuf, PathBuf),
+    Removed(PathBuf),
+}
+
+fn store_dir_for_path<'a>(store_dirs: &'a [StoreDir], path: &Path) -> Option<&'a StoreDir> {
+    store_dirs
+        .iter()
+        .filter(|x| path.starts_with(x.path()))
+        .max_by_key(|x| x.path().len())
+}
+
+fn events_to_changes(events: Vec<Event>) -> Vec<StoreChange> {
+    // Renames within the stores also come through as separate from and to events, which we don't want to handle twice
+    let renames = events
+        .iter()
+        .filter(|x| x.kind == EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+        .filter_map(|x| x.tracker())
+        .collect::<HashSet<usize>>();
+
+    let mut changes = Vec::new();
+
+    for event in events {
+        let part_of_rename = event.tracker().is_some_and(|x| renames.contains(&x));
+
+        let new_changes = match event.kind {
+            EventKind::Create(_)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+            | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+                if !part_of_rename =>
+            {
+                event.paths.into_iter().map(StoreChange::Changed).collect()
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => match &event.paths[..] {
+                [from, to] => vec![StoreChange::Moved(from.clone(), to.clone())],
+                _ => vec![],
+            },
+            // Events without a tracker are for a watched dir moving itself, which its parent already tells us about
+            EventKind::Modify(ModifyKind::Name(RenameMode::From))
+                if !part_of_rename && event.tracker().is_some() =>
+            {
+                event.paths.into_iter().map(StoreChange::Removed).collect()
+            }
+            EventKind::Remove(_) => event.paths.into_iter().map(StoreChange::Removed).collect(),
+            _ => vec![],
+        };
+
+        changes.extend(new_changes);
+    }
+
+    // Files being written fire plenty of events, only the last one matters
+    let mut seen = HashSet::new();
+    let mut changes = changes
+        .into_iter()
+        .rev()
+        .filter(|x| seen.insert(x.clone()))
+        .collect::<Vec<StoreChange>>();
+
+    changes.reverse();
+    changes
+}
+
+fn import_store_tree(
+    connection: &SqliteConnection,
+    store_dir: &StoreDir,
+    path: &Path,
+    autotaggers: &Autotaggers,
+) {
+    let mut rules = StoreRules::new(store_dir.path());
+
+    let mut walker = walkdir::WalkDir::new(path)
+        .follow_links(store_dir.symlinks().follows())
+        .sort_by_file_name()
+        .into_iter();
+
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else {
+            continue;
+        };
+
+        // No point looking through everything in an ignored dir just to skip each of it
+        if entry.file_type().is_dir() && rules.ignores_path(entry.path(), true) {
+            walker.skip_current_dir();
+            continue;
+        }
+
+        import_store_path(connection, store_dir, entry.path(), vec![], autotaggers);
+    }
+}
+
+fn handle_change(
+    connection: &SqliteConnection,
+    store_dirs: &[StoreDir],
+    change: StoreChange,
+    autotaggers: &Autotaggers,
+) {
+    match change {
+        StoreChange::Changed(path) => {
+            let Some(store_dir) = store_dir_for_path(store_dirs, &path) else {
+                return;
+            };
+
+            let point_path = store_point_path(store_dir.path(), &path);
+
+            // New rules can change the tags of, or bring in, anything beneath them
+            if point_path == path
+                && path
+                    .file_name()
+                    .is_some_and(|x| RULE_FILES.iter().any(|rule_file| x == *rule_file))
+            {
+                if let Some(parent) = path.parent() {
+                    import_store_tree(connection, store_dir, parent, autotaggers);
+                }
+                return;
+            }
+
+            // Anything moved or copied in as a whole directory won't get events for what's inside it
+            if point_path == path && path.is_dir() && path.file_name() != Some("@dir".as_ref()) {
+                import_store_tree(connection, store_dir, &path, autotaggers);
+            } else {
+                import_store_path(connection, store_dir, &point_path, vec![], autotaggers);
+            }
+        }
+        StoreChange::Removed(path) => {
+            let Some(store_dir) = store_dir_for_path(store_dirs, &path) else {
+                return;
+            };
+
+            let point_path = store_point_path(store_dir.path(), &path);
+
+            // Removing something from inside a dir point changes that point rather than removing it
+            if point_path == path {
+                forget_store_path(connection, &path);
+            } else {
+                import_store_path(connection, store_dir, &point_path, vec![], autotaggers);
+            }
+        }
+        StoreChange::Moved(from, to) => {
+            let (Some(from_store_dir), Some(to_store_dir)) = (
+                store_dir_for_path(store_dirs, &from),
+                store_dir_for_path(store_dirs, &to),
+            ) else {
+                return;
+            };
+
+            if store_point_path(from_store_dir.path(), &from) != from
+                || store_point_path(to_store_dir.path(), &to) != to
+            {
+                handle_change(
+                    connection,
+                    store_dirs,
+                    StoreChange::Removed(from),
+                    autotaggers,
+                );
+                handle_change(
+                    connection,
+                    store_dirs,
+                    StoreChange::Changed(to),
+                    autotaggers,
+                );
+                return;
+            }
+
+            if to.is_dir() && to.file_name() != Some("@dir".as_ref()) {
+                // A tag directory was moved, so everything in it moved along with it
+                for entry in walkdir::WalkDir::new(&to).sort_by_file_name() {
+                    let Ok(entry) = entry else {
+                        continue;
+                    };
+
+                    let Ok(rel_path) = entry.path().strip_prefix(&to) else {
+                        continue;
+                    };
+
+                    relocate_store_path(
+                        connection,
+                        (from_store_dir, &from.join(rel_path)),
+                        (to_store_dir, entry.path()),
+                        autotaggers,
+                    );
+                }
+            } else {
+                relocate_store_path(
+                    connection,
+                    (from_store_dir, &from),
+                    (to_store_dir, &to),
+                    autotaggers,
+                );
+            }
+        }
+    }
+}
+
+fn watch_stores(cfg: FfsConfig, customize: fn(&mut Autotaggers)) {
+    let connection = SqliteConnection::establish(&cfg.db_url).expect("Error connecting to db");
+    configure_connection(&connection);
+
+    let mut autotaggers = Autotaggers::from_config(&cfg);
+    customize(&mut autotaggers);
+
+    let store_dirs = cfg
+        .store_dir
+        .iter()
+        .chain(cfg.delegate_dirs.iter())
+        .cloned()
+        .collect::<Vec<StoreDir>>();
+
+    let (tx, rx) = mpsc::channel();
+
+    let mut watcher = notify::recommended_watcher(tx).expect("Error creating store watcher");
+
+    for store_dir in &store_dirs {
+        watcher
+            .watch(Path::new(store_dir.path()), RecursiveMode::Recursive)
+            .expect("Error watching store dir");
+    }
+
+    while let Ok(event) = rx.recv() {
+        let mut events = vec![event];
+
+        while let Ok(event) = rx.recv_timeout(DEBOUNCE) {
+            events.push(event);
+        }
+
+        let events = events
+            .into_iter()
+            .filter_map(|x| match x {
+                Ok(event) => Some(event),
+                Err(err) => {
+                    error!("Error watching stores: {:?}", err);
+                    None
+                }
+            })
+            .collect::<Vec<Event>>();
+
+        // Nothing that goes wrong here can be allowed to stop the watcher, the stores would silently drift out of sync with the mount
+        for change in events_to_changes(events) {
+            debug!("Store change: {:?}", change);
+
+            let change_str = format!("{:?}", change);
+
+            // Writes are committed path by path as they go rather than held for the whole burst, hashing and autotagging a
+            // directory dropped in can take minutes and the mount and CLI would time out waiting on the database meanwhile
+            let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+                handle_change(&connection, &store_dirs, change, &autotaggers)
+            }));
+
+            if handled.is_err() {
+                error!(
+                    "Dropping the rest of store change {} after it failed",
+                    change_str
+                );
+            }
+        }
+    }
+}
+
+/// Keeps the database in sync with the store and delegate dirs as files in them are created, moved and deleted
+pub fn spawn_store_watcher(
+    cfg: FfsConfig,
+    customize: fn(&mut Autotaggers),
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || watch_stores(cfg, customize))
+}